@@ -0,0 +1,324 @@
+/// Chip profiles for the parts the bootloader mock can pretend to be.
+/// each profile describes the memory map bossac expects to find and
+/// the identification registers it reads with `bossac -i`.
+use super::flash_utility::Device;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Chip {
+    Samd21g18,
+    Samd21e18,
+    Samd51j19,
+    Sam3x8e,
+}
+
+impl Chip {
+    pub const ALL: [Chip; 4] = [
+        Chip::Samd21g18,
+        Chip::Samd21e18,
+        Chip::Samd51j19,
+        Chip::Sam3x8e,
+    ];
+
+    pub fn profile(&self) -> &'static ChipProfile {
+        match self {
+            Chip::Samd21g18 => &SAMD21G18,
+            Chip::Samd21e18 => &SAMD21E18,
+            Chip::Samd51j19 => &SAMD51J19,
+            Chip::Sam3x8e => &SAM3X8E,
+        }
+    }
+
+    /// The register holding the chip id for a cpu with this cpuid,
+    /// cortex-m0+ and m4 parts have a dsu, the sam3 has a chipid block.
+    pub fn id_register_address(cpuid: u32) -> u32 {
+        match cpuid & CPUID_PARTNO {
+            CORTEX_M0P | CORTEX_M4 => DSU_DID,
+            _ => CHIPID_CIDR,
+        }
+    }
+
+    /// Finds the chip with these id register values, the silicon
+    /// revision is ignored.
+    pub fn identify(cpuid: u32, id: u32) -> Option<Chip> {
+        let id_address = Chip::id_register_address(cpuid);
+        let revision = match id_address {
+            DSU_DID => DSU_DID_REVISION,
+            _ => CHIPID_VERSION,
+        };
+        Chip::ALL.into_iter().find(|chip| {
+            let registers = chip.profile().id_registers;
+            let matches = |address: u32, value: u32, ignore: u32| {
+                registers
+                    .iter()
+                    .any(|r| r.address == address && r.value & !ignore == value & !ignore)
+            };
+            matches(CPUID, cpuid, !CPUID_PARTNO) && matches(id_address, id, revision)
+        })
+    }
+}
+
+impl std::str::FromStr for Chip {
+    type Err = String;
+
+    fn from_str(s: &str) -> core::result::Result<Self, Self::Err> {
+        Chip::ALL
+            .into_iter()
+            .find(|c| c.profile().name.eq_ignore_ascii_case(s))
+            .ok_or_else(|| format!("unknown chip: {s}"))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryRange {
+    pub start: u32,
+    pub size: u32,
+}
+
+impl MemoryRange {
+    pub const fn new(start: u32, size: u32) -> Self {
+        Self { start, size }
+    }
+
+    pub fn end(&self) -> u32 {
+        self.start + self.size
+    }
+
+    pub fn contains(&self, address: u32) -> bool {
+        address >= self.start && address < self.end()
+    }
+}
+
+/// a register that identifies the chip, with the value it holds.
+#[derive(Debug, Clone, Copy)]
+pub struct IdRegister {
+    pub address: u32,
+    pub value: u32,
+}
+
+#[derive(Debug)]
+pub struct ChipProfile {
+    pub name: &'static str,
+    pub flash: MemoryRange,
+    pub sram: MemoryRange,
+    /// register blocks that should be readable, the id registers
+    /// live inside of these.
+    pub peripherals: &'static [MemoryRange],
+    pub page_size: u32,
+    /// smallest erasable unit, on the sam3 this is a single page.
+    pub row_size: u32,
+    pub bootloader_size: u32,
+    /// sram the host can use to stage data before it is written to flash.
+    pub buffer: u32,
+    pub id_registers: &'static [IdRegister],
+    /// base address of a samd21 style nvm controller, if the chip has one.
+    pub nvmctrl: Option<u32>,
+    /// base address of a sam3 style enhanced embedded flash controller.
+    pub eefc: Option<u32>,
+}
+
+impl ChipProfile {
+    /// first address past the bootloader.
+    pub fn app_start(&self) -> u32 {
+        self.flash.start + self.bootloader_size
+    }
+}
+
+impl Device for ChipProfile {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn flash_base(&self) -> u32 {
+        self.flash.start
+    }
+
+    fn flash_size(&self) -> u32 {
+        self.flash.size
+    }
+
+    fn page_size(&self) -> u32 {
+        self.page_size
+    }
+
+    fn row_size(&self) -> u32 {
+        self.row_size
+    }
+
+    fn sram_base(&self) -> u32 {
+        self.sram.start
+    }
+
+    fn sram_size(&self) -> u32 {
+        self.sram.size
+    }
+
+    fn bootloader_size(&self) -> u32 {
+        self.bootloader_size
+    }
+
+    fn buffer_address(&self) -> u32 {
+        self.buffer
+    }
+
+    fn nvmctrl_base(&self) -> Option<u32> {
+        self.nvmctrl
+    }
+
+    fn eefc_base(&self) -> Option<u32> {
+        self.eefc
+    }
+}
+
+pub(crate) const CPUID: u32 = 0xe000ed00;
+const CPUID_PARTNO: u32 = 0xfff0;
+const CORTEX_M0P: u32 = 0xc600;
+const CORTEX_M4: u32 = 0xc240;
+// samd device service unit, device identification register.
+const DSU_DID: u32 = 0x41002018;
+const DSU_DID_REVISION: u32 = 0xf00;
+// sam3 chip id register.
+const CHIPID_CIDR: u32 = 0x400e0940;
+const CHIPID_VERSION: u32 = 0x1f;
+// sam3 eefc status register, FRDY is set once a command has finished.
+pub(crate) const EEFC_FSR: u32 = 0x08;
+pub(crate) const EEFC_FSR_FRDY: u32 = 1 << 0;
+// set when a command hit a locked region, cleared by reading FSR.
+pub(crate) const EEFC_FSR_FLOCKE: u32 = 1 << 2;
+
+const SAMD21_PERIPHERALS: &[MemoryRange] = &[
+    // system control block, holds cpuid.
+    MemoryRange::new(0xe000ed00, 0x100),
+    // sysctrl
+    MemoryRange::new(0x40000800, 0x100),
+    // dsu
+    MemoryRange::new(0x41002000, 0x100),
+    // nvmctrl
+    MemoryRange::new(0x41004000, 0x100),
+];
+
+const SAMD21G18: ChipProfile = ChipProfile {
+    name: "samd21g18",
+    flash: MemoryRange::new(0x0, 0x40000),
+    sram: MemoryRange::new(0x20000000, 0x8000),
+    peripherals: SAMD21_PERIPHERALS,
+    page_size: 64,
+    row_size: 256,
+    bootloader_size: 0x2000,
+    buffer: 0x20004000,
+    id_registers: &[
+        // cortex-m0+ r0p1
+        IdRegister {
+            address: CPUID,
+            value: 0x410cc601,
+        },
+        IdRegister {
+            address: DSU_DID,
+            value: 0x10010005,
+        },
+    ],
+    nvmctrl: Some(0x41004000),
+    eefc: None,
+};
+
+const SAMD21E18: ChipProfile = ChipProfile {
+    name: "samd21e18",
+    id_registers: &[
+        IdRegister {
+            address: CPUID,
+            value: 0x410cc601,
+        },
+        IdRegister {
+            address: DSU_DID,
+            value: 0x1001000a,
+        },
+    ],
+    ..SAMD21G18
+};
+
+const SAMD51J19: ChipProfile = ChipProfile {
+    name: "samd51j19",
+    flash: MemoryRange::new(0x0, 0x80000),
+    sram: MemoryRange::new(0x20000000, 0x30000),
+    peripherals: &[
+        MemoryRange::new(0xe000ed00, 0x100),
+        MemoryRange::new(0x41002000, 0x100),
+        MemoryRange::new(0x41004000, 0x100),
+    ],
+    page_size: 512,
+    // samd51 erases in blocks of 16 pages.
+    row_size: 8192,
+    bootloader_size: 0x4000,
+    buffer: 0x20004000,
+    id_registers: &[
+        // cortex-m4 r0p1
+        IdRegister {
+            address: CPUID,
+            value: 0x410fc241,
+        },
+        IdRegister {
+            address: DSU_DID,
+            value: 0x60060005,
+        },
+    ],
+    // the samd51 nvmctrl has a different register layout and is not mocked.
+    nvmctrl: None,
+    eefc: None,
+};
+
+const SAM3X8E: ChipProfile = ChipProfile {
+    name: "sam3x8e",
+    flash: MemoryRange::new(0x80000, 0x80000),
+    sram: MemoryRange::new(0x20070000, 0x18000),
+    peripherals: &[
+        // boot memory mirror, the rom sam-ba is mapped here.
+        MemoryRange::new(0x0, 0x100),
+        MemoryRange::new(0xe000ed00, 0x100),
+        // chipid
+        MemoryRange::new(0x400e0900, 0x100),
+        // eefc0 and eefc1
+        MemoryRange::new(0x400e0a00, 0x400),
+    ],
+    page_size: 256,
+    row_size: 256,
+    // sam-ba lives in rom, all of flash is for the application.
+    bootloader_size: 0,
+    buffer: 0x20071000,
+    id_registers: &[
+        // cortex-m3 r2p0
+        IdRegister {
+            address: CPUID,
+            value: 0x412fc230,
+        },
+        IdRegister {
+            address: CHIPID_CIDR,
+            value: 0x285e0a60,
+        },
+    ],
+    nvmctrl: None,
+    eefc: Some(0x400e0a00),
+};
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn identify_chips() {
+        for chip in Chip::ALL {
+            let registers = chip.profile().id_registers;
+            assert_eq!(
+                Chip::identify(registers[0].value, registers[1].value),
+                Some(chip)
+            );
+        }
+        // a newer silicon revision is still the same chip.
+        assert_eq!(
+            Chip::identify(0x410cc601, 0x10010305),
+            Some(Chip::Samd21g18)
+        );
+        assert_eq!(Chip::identify(0x412fc231, 0x285e0a61), Some(Chip::Sam3x8e));
+        // right did, wrong core.
+        assert_eq!(Chip::identify(0x410fc241, 0x10010005), None);
+        assert_eq!(Chip::identify(0x410cc601, 0x10010099), None);
+    }
+}
@@ -1,8 +1,22 @@
 use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::path::Path;
 
 /// this is named flash but its more just like block memory stuff.
 use super::{Error, Result};
 
+// on disk image layout, numbers are little endian.
+// magic, block count, then for each block its address, size and data.
+const IMAGE_MAGIC: &[u8; 8] = b"BORGFLSH";
+
+/// End of the range, ranges wrapping past the top of the address
+/// space are out of bounds.
+fn range_end(address: u32, length: u32) -> Result<u32> {
+    address
+        .checked_add(length)
+        .ok_or(Error::FlashOutOfBounds(address, length))
+}
+
 pub struct FlashBlock {
     address: u32,
     size: u32,
@@ -21,7 +35,8 @@ impl FlashBlock {
 
     pub fn write(&mut self, address: u32, data: &[u8]) -> Result<()> {
         println!("Flash write: {:x} {:x?}", address, data);
-        if address < self.address || address + data.len() as u32 > self.address + self.size {
+        let end = range_end(address, data.len() as u32)?;
+        if address < self.address || end > self.address + self.size {
             return Err(Error::FlashOutOfBounds(address, data.len() as u32));
         }
         for (addr, d) in data.iter().enumerate() {
@@ -32,7 +47,7 @@ impl FlashBlock {
     }
 
     pub fn read(&mut self, address: u32, length: u32) -> Result<Vec<u8>> {
-        if address < self.address || address + length > self.address + self.size {
+        if address < self.address || range_end(address, length)? > self.address + self.size {
             println!("Out of bounds: {:x} {}", address, length);
             return Err(Error::FlashOutOfBounds(address, length));
         }
@@ -47,7 +62,7 @@ impl FlashBlock {
     }
 
     pub fn erase(&mut self, address: u32, length: u32) -> Result<()> {
-        if address < self.address || address + length > self.address + self.size {
+        if address < self.address || range_end(address, length)? > self.address + self.size {
             return Err(Error::FlashOutOfBounds(address, length));
         }
 
@@ -71,8 +86,9 @@ impl Flash {
     /// flash blocks available.
     pub fn write(&mut self, address: u32, data: &[u8]) -> Result<()> {
         println!("Writing data to {:x} of {}", address, data.len());
+        let end = range_end(address, data.len() as u32)?;
         for (key, block) in self.flash_blocks.iter_mut() {
-            if address >= *key && (address + data.len() as u32) <= (key + block.size) {
+            if address >= *key && end <= (key + block.size) {
                 return block.write(address, data);
             }
             println!("Checking {:x} {}", key, block.size);
@@ -82,9 +98,10 @@ impl Flash {
 
     pub fn read(&mut self, address: u32, length: u32) -> Result<Vec<u8>> {
         println!("Reading {} bytes at {:x}", length, address);
+        let end = range_end(address, length)?;
         for (key, block) in self.flash_blocks.iter_mut() {
             println!("Checking the following blocks: {key:x} {}", block.size);
-            if address >= *key && (address + length) <= key + block.size {
+            if address >= *key && end <= key + block.size {
                 // println!("Reading from block: {:x}", key);
                 return block.read(address, length);
             }
@@ -93,8 +110,9 @@ impl Flash {
     }
 
     pub fn erase(&mut self, address: u32, length: u32) -> Result<()> {
+        let end = range_end(address, length)?;
         for (key, block) in self.flash_blocks.iter_mut() {
-            if address >= *key && (address + length) <= key + block.size {
+            if address >= *key && end <= key + block.size {
                 // println!("Reading from block: {:x}", key);
                 return block.erase(address, length);
             }
@@ -102,7 +120,18 @@ impl Flash {
         Err(Error::FlashOutOfBounds(address, length))
     }
 
+    /// true if the whole range is inside one block.
+    pub fn contains(&self, address: u32, length: u32) -> bool {
+        let Ok(end) = range_end(address, length) else {
+            return false;
+        };
+        self.flash_blocks
+            .iter()
+            .any(|(key, block)| address >= *key && end <= key + block.size)
+    }
+
     pub fn add_block(&mut self, start_address: u32, size: u32) -> Result<()> {
+        let end = range_end(start_address, size)?;
         for (block_start_address, block) in self.flash_blocks.iter_mut() {
             println!("Checking {block_start_address} on {0}", block.size);
             println!(
@@ -119,14 +148,12 @@ impl Flash {
             }
             println!(
                 "{} >= {} && {} <= {}",
-                start_address + size,
+                end,
                 *block_start_address,
-                start_address + size,
+                end,
                 *block_start_address + block.size
             );
-            if start_address + size >= *block_start_address
-                && start_address + size < *block_start_address + block.size
-            {
+            if end >= *block_start_address && end < *block_start_address + block.size {
                 return Err(Error::FlashOverLap);
             }
         }
@@ -135,6 +162,74 @@ impl Flash {
             .insert(start_address, FlashBlock::new(start_address, size));
         Ok(())
     }
+
+    /// Writes every block out as an image.
+    pub fn save<W: Write>(&self, out: &mut W) -> Result<()> {
+        let mut addresses: Vec<&u32> = self.flash_blocks.keys().collect();
+        addresses.sort();
+        out.write_all(IMAGE_MAGIC)?;
+        out.write_all(&(addresses.len() as u32).to_le_bytes())?;
+        for address in addresses {
+            let block = &self.flash_blocks[address];
+            out.write_all(&block.address.to_le_bytes())?;
+            out.write_all(&block.size.to_le_bytes())?;
+            out.write_all(&block.data)?;
+        }
+        Ok(())
+    }
+
+    /// Restores blocks from an image, every block in it has to exist
+    /// already with the same size. the whole image is read and checked
+    /// before anything is restored, a bad image leaves flash untouched.
+    pub fn load<R: Read>(&mut self, input: &mut R) -> Result<()> {
+        let mut magic = [0; 8];
+        input.read_exact(&mut magic)?;
+        if &magic != IMAGE_MAGIC {
+            return Err(Error::InvalidImage("bad magic"));
+        }
+        let count = read_u32(input)?;
+        let mut blocks = vec![];
+        for _ in 0..count {
+            let address = read_u32(input)?;
+            let size = read_u32(input)?;
+            range_end(address, size)?;
+            // the size is checked before it is trusted with an allocation.
+            match self.flash_blocks.get(&address) {
+                None => return Err(Error::InvalidImage("block is not in the memory map")),
+                Some(block) if block.size != size => {
+                    return Err(Error::InvalidImage("block size does not match"));
+                }
+                Some(_) => {}
+            }
+            let mut data = vec![0; size as usize];
+            input.read_exact(&mut data)?;
+            blocks.push((address, data));
+        }
+        for (address, data) in blocks {
+            if let Some(block) = self.flash_blocks.get_mut(&address) {
+                block.data = data;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn save_file(&self, path: &Path) -> Result<()> {
+        let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        self.save(&mut file)?;
+        file.flush()?;
+        Ok(())
+    }
+
+    pub fn load_file(&mut self, path: &Path) -> Result<()> {
+        let mut file = std::io::BufReader::new(std::fs::File::open(path)?);
+        self.load(&mut file)
+    }
+}
+
+fn read_u32<R: Read>(input: &mut R) -> Result<u32> {
+    let mut bytes = [0; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
 }
 
 #[cfg(test)]
@@ -158,6 +253,58 @@ mod test {
         assert_eq!(flash_b.read(0x100, 3).unwrap(), vec![1, 2, 3]);
     }
 
+    #[test]
+    fn image_round_trip() {
+        let mut flash = Flash::default();
+        flash.add_block(0x0, 0x100).unwrap();
+        flash.add_block(0x2000, 0x10).unwrap();
+        flash.write(0x10, &[1, 2, 3]).unwrap();
+        flash.write(0x2004, &[4, 5]).unwrap();
+        let mut image = vec![];
+        flash.save(&mut image).unwrap();
+
+        let mut restored = Flash::default();
+        restored.add_block(0x0, 0x100).unwrap();
+        restored.add_block(0x2000, 0x10).unwrap();
+        restored.load(&mut image.as_slice()).unwrap();
+        assert_eq!(restored.read(0x10, 3).unwrap(), vec![1, 2, 3]);
+        assert_eq!(restored.read(0x2004, 2).unwrap(), vec![4, 5]);
+
+        let mut mismatch = Flash::default();
+        mismatch.add_block(0x0, 0x80).unwrap();
+        assert!(mismatch.load(&mut image.as_slice()).is_err());
+        assert!(Flash::default().load(&mut &b"garbage!"[..]).is_err());
+    }
+
+    #[test]
+    fn bad_images_change_nothing() {
+        let mut flash = Flash::default();
+        flash.add_block(0x0, 0x100).unwrap();
+        flash.add_block(0x2000, 0x10).unwrap();
+        flash.write(0x2000, &[1, 2, 3]).unwrap();
+        let mut image = vec![];
+        flash.save(&mut image).unwrap();
+        flash.write(0x10, &[7]).unwrap();
+
+        // cut off in the middle of the second block.
+        let truncated = &image[..image.len() - 4];
+        assert!(flash.load(&mut &truncated[..]).is_err());
+        assert_eq!(flash.read(0x10, 1).unwrap(), vec![7]);
+
+        // a block wrapping past the top of the address space.
+        let mut corrupt = IMAGE_MAGIC.to_vec();
+        for word in [1_u32, 0xffffff00, 0x200] {
+            corrupt.extend(word.to_le_bytes());
+        }
+        assert!(flash.load(&mut corrupt.as_slice()).is_err());
+        // one that is not part of the memory map, with a huge size.
+        corrupt[12..16].copy_from_slice(&0x4000_u32.to_le_bytes());
+        corrupt[16..20].copy_from_slice(&0xfffffff_u32.to_le_bytes());
+        assert!(flash.load(&mut corrupt.as_slice()).is_err());
+        assert!(flash.add_block(0xffffff00, 0x200).is_err());
+        assert_eq!(flash.read(0x2000, 3).unwrap(), vec![1, 2, 3]);
+    }
+
     #[test]
     fn add_block_overlap() {
         let mut flash_b = Flash::default();
@@ -1,4 +1,11 @@
-mod utils;
+pub mod utils;
+
+use super::xmd_serial::{self, PacketSize, XmdSerial};
+use std::time::Duration;
+
+use super::chip::{CPUID, EEFC_FSR, EEFC_FSR_FLOCKE, EEFC_FSR_FRDY};
+use super::port_finder::{self, BoardMode, PortFinder, PortLister};
+use super::{Chip, Command, Opcode, TransportMode, TOUCH_BAUD};
 
 pub type Result<T> = core::result::Result<T, Error>;
 
@@ -6,16 +13,207 @@ pub type Result<T> = core::result::Result<T, Error>;
 pub enum Error {
     #[error("communication error: {0}")]
     CommErr(#[from] std::io::Error),
+
+    #[error("xmodem error: {0}")]
+    XModem(#[from] xmd_serial::Error),
+
+    #[error("serial port error: {0}")]
+    SerialPort(#[from] serialport::Error),
+
+    #[error("port discovery error: {0}")]
+    PortFinder(#[from] port_finder::Error),
+
+    #[error("timed out waiting for a reply")]
+    Timeout,
+
+    #[error("malformed reply: {0:x?}")]
+    MalformedReply(Vec<u8>),
+
+    #[error("verify failed at {0:x}")]
+    VerifyFailed(u32),
+
+    #[error("{0:x} is inside the bootloader, it takes force to write there")]
+    BootloaderRegion(u32),
+
+    #[error("operation cancelled")]
+    Cancelled,
+
+    #[error("{0:x} is not aligned to a row")]
+    Unaligned(u32),
+
+    #[error("unknown device, cpuid: {cpuid:08x} id: {id:08x}")]
+    UnknownDevice { cpuid: u32, id: u32 },
+
+    #[error("{0} is not supported on this device")]
+    Unsupported(&'static str),
+
+    #[error("{0:x} is protected by BOOTPROT or a lock bit")]
+    Protected(u32),
+
+    #[error("{size} bytes at {address:x} do not fit in memory")]
+    OutOfRange { address: u32, size: u32 },
 }
 
 /// Arduino flashing utility.
 /// specifically aimed at the arduino nano io 33.
 
 /// device specific information.
-trait Device {}
+pub trait Device {
+    fn name(&self) -> &str;
+    fn flash_base(&self) -> u32;
+    fn flash_size(&self) -> u32;
+    fn page_size(&self) -> u32;
+    /// smallest erasable unit of flash.
+    fn row_size(&self) -> u32;
+    fn sram_base(&self) -> u32;
+    fn sram_size(&self) -> u32;
+    fn bootloader_size(&self) -> u32;
+    /// sram the flasher stages data in, well clear of the bootloaders stack.
+    fn buffer_address(&self) -> u32;
+    /// base of a samd21 style nvm controller, used to erase single rows.
+    fn nvmctrl_base(&self) -> Option<u32>;
+    /// base of a sam3 style flash controller, used to set the gpnvm bits.
+    fn eefc_base(&self) -> Option<u32>;
+}
+
+/// What an incremental write sent to the device.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct WriteReport {
+    pub rows: u32,
+    pub rows_written: u32,
+    pub bytes_written: u32,
+    /// bytes that were already in flash and never went over the link.
+    pub bytes_saved: u32,
+    /// flash past the end of the data that got erased along the way,
+    /// without a nvm controller flash can only be erased to the end.
+    pub bytes_erased_past: u32,
+}
+
+/// The part of a flasher operation progress is reported for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Erase,
+    /// comparing flash with the new image before an incremental write.
+    Compare,
+    Write,
+    Verify,
+    Read,
+}
+
+/// Receives progress of long flasher operations, `done` and `total`
+/// are bytes except while erasing rows where they count rows.
+pub trait Progress: Send {
+    fn update(&mut self, phase: Phase, done: u32, total: u32);
+}
+
+impl<F> Progress for F
+where
+    F: FnMut(Phase, u32, u32) + Send,
+{
+    fn update(&mut self, phase: Phase, done: u32, total: u32) {
+        self(phase, done, total)
+    }
+}
+
+/// Cancels a flasher operation, checked between pages.
+/// clones share the flag so one can be handed to another thread.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(std::sync::Arc<std::sync::atomic::AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(std::sync::atomic::Ordering::Relaxed)
+    }
+}
+
+// amount of data moved through the sram buffer per 'Y' command.
+const BUFFER_SIZE: u32 = 0x1000;
+
+// nvmctrl registers used to erase a single row.
+const NVM_CTRLA: u32 = 0x00;
+const NVM_INTFLAG: u32 = 0x14;
+const NVM_ADDR: u32 = 0x1c;
+const NVM_CMD_ER: u32 = 0xa502;
+const NVM_STATUS: u32 = 0x18;
+const NVM_INTFLAG_READY: u8 = 1 << 0;
+const NVM_INTFLAG_ERROR: u8 = 1 << 1;
+// a write or erase of a protected row was rejected.
+const NVM_STATUS_LOCKE: u16 = 1 << 3;
+// polls of a flash controller status register before giving up.
+const NVM_READY_TRIES: u32 = 100;
+
+// eefc command register, the key has to be in the top byte.
+const EEFC_FCR: u32 = 0x04;
+const EEFC_KEY: u32 = 0x5a << 24;
+const EEFC_FCMD_SGPB: u32 = 0x0b;
+// gpnvm bit 1 selects booting from flash instead of the rom.
+const GPNVM_BOOT_FLASH: u32 = 1;
+
+// time given to a port that is not a known usb board to notice a touch.
+const TOUCH_SETTLE: Duration = Duration::from_millis(500);
+
+// writing this to AIRCR requests a system reset.
+const AIRCR: u32 = 0xe000ed0c;
+const AIRCR_SYSRESETREQ: u32 = 0x05fa0004;
+
+/// Opens `port` at 1200 baud and closes it again, arduino samd boards
+/// take that as a request to reset into their bootloader.
+pub fn touch_1200(port: &str) -> Result<()> {
+    let mut serial = serialport::new(port, TOUCH_BAUD)
+        .timeout(Duration::from_millis(100))
+        .open()?;
+    // ptys have no modem lines, the baud rate alone is enough there.
+    if let Err(e) = serial.write_data_terminal_ready(false) {
+        println!("could not drop dtr on {port}: {e}");
+    }
+    Ok(())
+}
+
+/// Resets the board on `port` into its bootloader and returns the port
+/// the bootloader shows up on, usb boards re-enumerate and may come
+/// back on a different port.
+pub fn enter_bootloader<L: PortLister>(
+    port: &str,
+    finder: &PortFinder<L>,
+    timeout: Duration,
+) -> Result<String> {
+    let board = finder
+        .ports()?
+        .into_iter()
+        .find(|p| p.name == port)
+        .and_then(|p| p.board());
+    if board.is_some_and(|b| b.mode == BoardMode::Bootloader) {
+        return Ok(port.to_string());
+    }
+    touch_1200(port)?;
+    match board {
+        Some(_) => Ok(finder
+            .wait_for_board(BoardMode::Bootloader, timeout)?
+            .port
+            .name),
+        // ports discovery knows nothing about, like the emulators pty,
+        // keep their name.
+        None => {
+            std::thread::sleep(TOUCH_SETTLE);
+            Ok(port.to_string())
+        }
+    }
+}
 
 pub struct Flasher<C> {
-    comm: C,
+    comm: ArduinoBootComm<C>,
+    device: &'static dyn Device,
+    progress: Option<Box<dyn Progress>>,
+    cancel: CancelToken,
+    force: bool,
 }
 
 impl<C> Flasher<C>
@@ -23,38 +221,619 @@ where
     C: std::io::Write + std::io::Read,
 {
     pub fn new(comm: C) -> Self {
-        Self { comm }
+        Self::with_device(ArduinoBootComm::new(comm), Chip::Samd21g18.profile())
+    }
+
+    pub fn with_device(comm: ArduinoBootComm<C>, device: &'static dyn Device) -> Self {
+        Self {
+            comm,
+            device,
+            progress: None,
+            cancel: CancelToken::new(),
+            force: false,
+        }
+    }
+
+    /// Asks the chip what it is and picks the matching device.
+    pub fn identify(mut comm: ArduinoBootComm<C>) -> Result<Self> {
+        let cpuid = comm.read_word(CPUID)?;
+        let id = comm.read_word(Chip::id_register_address(cpuid))?;
+        let chip = Chip::identify(cpuid, id).ok_or(Error::UnknownDevice { cpuid, id })?;
+        Ok(Self::with_device(comm, chip.profile()))
+    }
+
+    pub fn device(&self) -> &'static dyn Device {
+        self.device
+    }
+
+    pub fn comm(&mut self) -> &mut ArduinoBootComm<C> {
+        &mut self.comm
+    }
+
+    /// Report progress of every following operation to `progress`.
+    pub fn set_progress(&mut self, progress: Box<dyn Progress>) {
+        self.progress = Some(progress);
+    }
+
+    /// Operations stop with `Error::Cancelled` once `cancel` is cancelled,
+    /// whatever was written before that stays in flash.
+    pub fn set_cancel_token(&mut self, cancel: CancelToken) {
+        self.cancel = cancel;
+    }
+
+    /// Allow erasing and writing below the application start,
+    /// which is where the bootloader lives.
+    pub fn set_force(&mut self, force: bool) {
+        self.force = force;
+    }
+
+    fn check_app_region(&self, offset: u32) -> Result<()> {
+        if offset < self.device.bootloader_size() && !self.force {
+            return Err(Error::BootloaderRegion(self.device.flash_base() + offset));
+        }
+        Ok(())
+    }
+
+    /// Fails unless `size` bytes from `offset` fit in flash.
+    fn check_range(&self, offset: u32, size: u32) -> Result<()> {
+        match offset.checked_add(size) {
+            Some(end) if end <= self.device.flash_size() => Ok(()),
+            _ => Err(Error::OutOfRange {
+                address: self.device.flash_base().saturating_add(offset),
+                size,
+            }),
+        }
+    }
+
+    fn report(&mut self, phase: Phase, done: u32, total: u32) {
+        if let Some(progress) = self.progress.as_mut() {
+            progress.update(phase, done, total);
+        }
+    }
+
+    /// Reports progress, and bails out if the operation was cancelled.
+    fn step(&mut self, phase: Phase, done: u32, total: u32) -> Result<()> {
+        if self.cancel.is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+        self.report(phase, done, total);
+        Ok(())
+    }
+
+    /// Erases flash from `offset` to the end, the bootloader decides
+    /// how much of the start of flash it protects.
+    pub fn erase(&mut self, offset: u32) -> Result<()> {
+        self.check_app_region(offset)?;
+        self.check_range(offset, 0)?;
+        let total = self.device.flash_size() - offset;
+        self.step(Phase::Erase, 0, total)?;
+        self.lock_error()?;
+        self.comm.erase(self.device.flash_base() + offset)?;
+        self.check_locked(self.device.flash_base() + offset)?;
+        self.report(Phase::Erase, total, total);
+        Ok(())
+    }
+
+    /// Writes data to flash at `offset`, the flash has to be erased first.
+    /// data goes to the sram buffer and is then copied to flash with 'Y'.
+    pub fn write(&mut self, offset: u32, data: &[u8]) -> Result<()> {
+        self.check_app_region(offset)?;
+        self.check_range(offset, data.len() as u32)?;
+        let total = data.len() as u32;
+        self.comm.set_buffer_address(self.device.buffer_address())?;
+        self.write_pages(offset, data, 0, total)?;
+        self.report(Phase::Write, total, total);
+        Ok(())
+    }
+
+    /// Writes data a buffer at a time, `done` and `total` are only
+    /// used for progress.
+    fn write_pages(&mut self, offset: u32, data: &[u8], done: u32, total: u32) -> Result<()> {
+        let buffer = self.device.buffer_address();
+        let page_size = self.device.page_size() as usize;
+        let mut address = self.device.flash_base() + offset;
+        // a flag left over from an earlier session is not ours.
+        self.lock_error()?;
+        for (i, chunk) in data.chunks(BUFFER_SIZE as usize).enumerate() {
+            self.step(Phase::Write, done + i as u32 * BUFFER_SIZE, total)?;
+            // the bootloader writes whole pages.
+            let mut chunk = chunk.to_vec();
+            chunk.resize(chunk.len().div_ceil(page_size) * page_size, 0xff);
+            self.comm.send_buffer(buffer, &chunk)?;
+            self.comm.write_buffer(address, chunk.len() as u32)?;
+            self.check_locked(address)?;
+            address += chunk.len() as u32;
+        }
+        Ok(())
+    }
+
+    /// Writes data to flash at `offset`, but only the rows that differ
+    /// from what the device already has. pages are compared by crc with
+    /// 'Z', or read back when the bootloader does not answer 'Z'.
+    /// `offset` has to be row aligned, rows are erased as needed.
+    /// on devices without a nvm controller everything after the first
+    /// changed row is erased, including flash past the end of data,
+    /// `WriteReport::bytes_erased_past` says how much of that there was.
+    pub fn write_changed(&mut self, offset: u32, data: &[u8]) -> Result<WriteReport> {
+        let row_size = self.device.row_size();
+        let page_size = self.device.page_size() as usize;
+        if !offset.is_multiple_of(row_size) {
+            return Err(Error::Unaligned(offset));
+        }
+        self.check_app_region(offset)?;
+        self.check_range(offset, data.len() as u32)?;
+
+        let total = data.len() as u32;
+        let mut use_crc = true;
+        let mut changed = vec![];
+        for (i, row) in data.chunks(row_size as usize).enumerate() {
+            let row_offset = i as u32 * row_size;
+            for (j, page) in row.chunks(page_size).enumerate() {
+                let page_offset = row_offset + (j * page_size) as u32;
+                self.step(Phase::Compare, page_offset, total)?;
+                if self.page_changed(offset + page_offset, page, &mut use_crc)? {
+                    changed.push(i);
+                    break;
+                }
+            }
+        }
+        self.report(Phase::Compare, total, total);
+
+        let mut report = WriteReport {
+            rows: data.len().div_ceil(row_size as usize) as u32,
+            ..Default::default()
+        };
+        // byte ranges of data that have to be written.
+        let mut runs = vec![];
+        match (self.device.nvmctrl_base(), changed.first()) {
+            (_, None) => {}
+            (Some(nvmctrl), _) => {
+                self.lock_error()?;
+                for (i, &row) in changed.iter().enumerate() {
+                    self.step(Phase::Erase, i as u32, changed.len() as u32)?;
+                    let address = self.device.flash_base() + offset + row as u32 * row_size;
+                    self.erase_row(nvmctrl, address)?;
+                    self.check_locked(address)?;
+                }
+                self.report(Phase::Erase, changed.len() as u32, changed.len() as u32);
+                // runs of neighbouring rows go out as one write.
+                let mut start = 0;
+                for i in 1..=changed.len() {
+                    if i == changed.len() || changed[i] != changed[i - 1] + 1 {
+                        let from = changed[start] * row_size as usize;
+                        let to = ((changed[i - 1] + 1) * row_size as usize).min(data.len());
+                        runs.push(from..to);
+                        start = i;
+                    }
+                }
+                report.rows_written = changed.len() as u32;
+            }
+            (None, Some(&first)) => {
+                // without row erase everything from the first change is redone.
+                let from = first * row_size as usize;
+                self.erase(offset + from as u32)?;
+                runs.push(from..data.len());
+                report.rows_written = report.rows - first as u32;
+                report.bytes_erased_past = self.device.flash_size() - offset - data.len() as u32;
+            }
+        }
+
+        report.bytes_written = runs.iter().map(|run| run.len() as u32).sum();
+        if !runs.is_empty() {
+            self.comm.set_buffer_address(self.device.buffer_address())?;
+        }
+        let mut done = 0;
+        for run in runs {
+            let data = &data[run.clone()];
+            self.write_pages(offset + run.start as u32, data, done, report.bytes_written)?;
+            done += data.len() as u32;
+        }
+        self.report(Phase::Write, done, report.bytes_written);
+        report.bytes_saved = data.len() as u32 - report.bytes_written;
+        Ok(report)
+    }
+
+    fn page_changed(&mut self, offset: u32, page: &[u8], use_crc: &mut bool) -> Result<bool> {
+        // the device pads pages with erased bytes.
+        let mut page = page.to_vec();
+        page.resize(self.device.page_size() as usize, 0xff);
+        let address = self.device.flash_base() + offset;
+        if *use_crc {
+            match self.comm.checksum(address, page.len() as u32) {
+                Ok(crc) => return Ok(crc != xmd_serial::crc16(&page)),
+                // older bootloaders silently ignore 'Z'.
+                Err(Error::Timeout) => *use_crc = false,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(self.comm.receive_buffer(address, page.len() as u32)? != page)
+    }
+
+    /// Reads and clears the lock error of the flash controller, writes and
+    /// erases of protected flash are rejected silently otherwise.
+    fn lock_error(&mut self) -> Result<bool> {
+        if let Some(nvmctrl) = self.device.nvmctrl_base() {
+            let status = self.comm.read_half_word(nvmctrl + NVM_STATUS)?;
+            if status & NVM_STATUS_LOCKE == 0 {
+                return Ok(false);
+            }
+            // both flags clear by writing a one.
+            self.comm
+                .write_half_word(nvmctrl + NVM_STATUS, NVM_STATUS_LOCKE)?;
+            self.comm
+                .write_byte(nvmctrl + NVM_INTFLAG, NVM_INTFLAG_ERROR)?;
+            return Ok(true);
+        }
+        if let Some(eefc) = self.device.eefc_base() {
+            return Ok(self.comm.read_word(eefc + EEFC_FSR)? & EEFC_FSR_FLOCKE != 0);
+        }
+        Ok(false)
+    }
+
+    fn check_locked(&mut self, address: u32) -> Result<()> {
+        match self.lock_error()? {
+            true => Err(Error::Protected(address)),
+            false => Ok(()),
+        }
+    }
+
+    /// Erases the row at `address` through the nvm controller.
+    fn erase_row(&mut self, nvmctrl: u32, address: u32) -> Result<()> {
+        // ADDR takes a 16 bit word address.
+        self.comm.write_word(nvmctrl + NVM_ADDR, address / 2)?;
+        self.comm.write_word(nvmctrl + NVM_CTRLA, NVM_CMD_ER)?;
+        for _ in 0..NVM_READY_TRIES {
+            if self.comm.read_byte(nvmctrl + NVM_INTFLAG)? & NVM_INTFLAG_READY != 0 {
+                return Ok(());
+            }
+        }
+        Err(Error::Timeout)
+    }
+
+    /// Reads flash back and compares it with data.
+    pub fn verify(&mut self, offset: u32, data: &[u8]) -> Result<()> {
+        let read = self.read_pages(Phase::Verify, offset, data.len() as u32)?;
+        match read.iter().zip(data).position(|(r, d)| r != d) {
+            Some(i) => Err(Error::VerifyFailed(
+                self.device.flash_base() + offset + i as u32,
+            )),
+            None => Ok(()),
+        }
+    }
+
+    pub fn read(&mut self, offset: u32, size: u32) -> Result<Vec<u8>> {
+        self.read_pages(Phase::Read, offset, size)
+    }
+
+    fn read_pages(&mut self, phase: Phase, offset: u32, size: u32) -> Result<Vec<u8>> {
+        self.check_range(offset, size)?;
+        let start = self.device.flash_base() + offset;
+        let mut data = Vec::with_capacity(size as usize);
+        while (data.len() as u32) < size {
+            self.step(phase, data.len() as u32, size)?;
+            let chunk = (size - data.len() as u32).min(BUFFER_SIZE);
+            data.extend(self.comm.receive_buffer(start + data.len() as u32, chunk)?);
+        }
+        self.report(phase, size, size);
+        Ok(data)
+    }
+
+    /// Makes the chip boot from flash instead of the sam-ba rom, only
+    /// chips with an eefc have a boot select bit.
+    pub fn set_boot_flash(&mut self) -> Result<()> {
+        let eefc = self
+            .device
+            .eefc_base()
+            .ok_or(Error::Unsupported("boot from flash"))?;
+        let command = EEFC_KEY | GPNVM_BOOT_FLASH << 8 | EEFC_FCMD_SGPB;
+        self.comm.write_word(eefc + EEFC_FCR, command)?;
+        for _ in 0..NVM_READY_TRIES {
+            if self.comm.read_word(eefc + EEFC_FSR)? & EEFC_FSR_FRDY != 0 {
+                return Ok(());
+            }
+        }
+        Err(Error::Timeout)
+    }
+
+    /// Resets the cpu, on an arduino this starts the application.
+    pub fn reset(&mut self) -> Result<()> {
+        self.comm.write_word(AIRCR, AIRCR_SYSRESETREQ)
     }
 }
 
 /// low level protocol handler for
 /// communicating to arduino bootloader.
+/// every sam-ba command has a method here, replies are checked
+/// byte for byte so a confused bootloader shows up as an error.
 pub struct ArduinoBootComm<C> {
     comm: C,
+    transport_mode: TransportMode,
+    terminal_mode: bool,
+    debug: bool,
+    // xmodem packet size used for 'S'.
+    packet_size: PacketSize,
 }
 
+// longest version string we accept before giving up on the "\n\r".
+const MAX_VERSION_LEN: usize = 256;
+
 impl<C> ArduinoBootComm<C>
 where
     C: std::io::Write + std::io::Read,
 {
     pub fn new(comm: C) -> Self {
-        Self { comm }
+        Self {
+            comm,
+            transport_mode: TransportMode::UartXmodem,
+            terminal_mode: false,
+            debug: false,
+            packet_size: PacketSize::Normal,
+        }
+    }
+
+    /// Pick how 'S' and 'R' data is framed, must match the bootloader.
+    pub fn set_transport_mode(&mut self, mode: TransportMode) {
+        self.transport_mode = mode;
+    }
+
+    /// Print commands and replies as they go over the link.
+    pub fn set_debug(&mut self, debug: bool) {
+        self.debug = debug;
+    }
+
+    /// Packet size for xmodem uploads, only use 1k packets with a
+    /// bootloader that accepts them, the stock SAM-BA one does not.
+    pub fn set_packet_size(&mut self, packet_size: PacketSize) {
+        self.packet_size = packet_size;
+    }
+
+    pub fn terminal_mode(&self) -> bool {
+        self.terminal_mode
+    }
+
+    fn send_command(&mut self, command: Command) -> Result<()> {
+        let bytes = command.encode();
+        if self.debug {
+            println!(">> {}", String::from_utf8_lossy(&bytes));
+        }
+        self.comm.write_all(&bytes)?;
+        Ok(())
+    }
+
+    /// read_exact, but running out of data is reported as a timeout.
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<()> {
+        self.comm.read_exact(buf).map_err(|e| match e.kind() {
+            std::io::ErrorKind::TimedOut | std::io::ErrorKind::UnexpectedEof => Error::Timeout,
+            _ => Error::CommErr(e),
+        })?;
+        if self.debug {
+            println!("<< {:x?}", buf);
+        }
+        Ok(())
+    }
+
+    fn expect_reply(&mut self, expected: &[u8]) -> Result<()> {
+        let mut reply = vec![0; expected.len()];
+        self.read_exact(&mut reply)?;
+        if reply != expected {
+            return Err(Error::MalformedReply(reply));
+        }
+        Ok(())
+    }
+
+    /// Reads up to and including the next "\n\r", which is dropped.
+    fn read_line(&mut self) -> Result<Vec<u8>> {
+        let mut line = vec![];
+        let mut byte = [0; 1];
+        while !line.ends_with(b"\n\r") {
+            if line.len() > MAX_VERSION_LEN {
+                return Err(Error::MalformedReply(line));
+            }
+            self.read_exact(&mut byte)?;
+            line.push(byte[0]);
+        }
+        line.truncate(line.len() - 2);
+        Ok(line)
+    }
+
+    // in terminal mode every reply is wrapped in "\n\r" ... ">".
+    fn reply_start(&mut self) -> Result<()> {
+        if self.terminal_mode {
+            self.expect_reply(b"\n\r")?;
+        }
+        Ok(())
+    }
+
+    fn reply_end(&mut self) -> Result<()> {
+        if self.terminal_mode {
+            self.expect_reply(b">")?;
+        }
+        Ok(())
+    }
+
+    /// Sends a command and checks its fixed reply.
+    fn command(&mut self, command: Command, reply: &[u8]) -> Result<()> {
+        self.send_command(command)?;
+        self.reply_start()?;
+        self.expect_reply(reply)?;
+        self.reply_end()
+    }
+
+    /// Reads the version string of the bootloader.
+    pub fn version(&mut self) -> Result<String> {
+        self.send_command(Command::bare(Opcode::Version))?;
+        self.reply_start()?;
+        let version = self.read_line()?;
+        self.reply_end()?;
+        String::from_utf8(version).map_err(|e| Error::MalformedReply(e.into_bytes()))
+    }
+
+    /// Switches the bootloader to human readable replies.
+    pub fn set_terminal_mode(&mut self) -> Result<()> {
+        self.send_command(Command::bare(Opcode::TerminalMode))?;
+        self.reply_start()?;
+        self.expect_reply(b"\n\r")?;
+        self.terminal_mode = true;
+        self.reply_end()
+    }
+
+    /// Switches the bootloader back to binary replies.
+    /// the reply is a single "\n\r" whichever mode it was in.
+    pub fn set_normal_mode(&mut self) -> Result<()> {
+        self.send_command(Command::bare(Opcode::NormalMode))?;
+        self.expect_reply(b"\n\r")?;
+        self.terminal_mode = false;
+        Ok(())
+    }
+
+    /// Reads `size` bytes with 'o', 'h' or 'w'.
+    fn peek(&mut self, opcode: Opcode, address: u32, size: usize) -> Result<u32> {
+        self.send_command(Command::new(opcode, address, size as u32))?;
+        self.reply_start()?;
+        let value = if self.terminal_mode {
+            // "0x" followed by two hex digits per byte.
+            let line = self.read_line()?;
+            let digits = line.strip_prefix(b"0x").filter(|d| d.len() == size * 2);
+            digits
+                .and_then(|d| std::str::from_utf8(d).ok())
+                .and_then(|d| u32::from_str_radix(d, 16).ok())
+                .ok_or(Error::MalformedReply(line))?
+        } else {
+            let mut bytes = [0; 4];
+            self.read_exact(&mut bytes[..size])?;
+            u32::from_le_bytes(bytes)
+        };
+        self.reply_end()?;
+        Ok(value)
+    }
+
+    /// Writes `value` with 'O', 'H' or 'W', these have no reply.
+    fn poke(&mut self, opcode: Opcode, address: u32, value: u32) -> Result<()> {
+        self.send_command(Command::new(opcode, address, value))?;
+        self.reply_start()?;
+        self.reply_end()
     }
 
-    /// Read a single byte at teh given address. 
     pub fn read_byte(&mut self, address: u32) -> Result<u8> {
-        self.comm.write_all(format!("o{:x},4#", address).as_bytes())?;
-        let mut bytes = [0; 1];
-        self.comm.read_exact(&mut bytes)?;
-        Ok(bytes[0])
+        Ok(self.peek(Opcode::ReadByte, address, 1)? as u8)
+    }
+
+    pub fn read_half_word(&mut self, address: u32) -> Result<u16> {
+        Ok(self.peek(Opcode::ReadHalfWord, address, 2)? as u16)
+    }
+
+    pub fn read_word(&mut self, address: u32) -> Result<u32> {
+        self.peek(Opcode::ReadWord, address, 4)
+    }
+
+    pub fn write_byte(&mut self, address: u32, value: u8) -> Result<()> {
+        self.poke(Opcode::WriteByte, address, value as u32)
+    }
+
+    pub fn write_half_word(&mut self, address: u32, value: u16) -> Result<()> {
+        self.poke(Opcode::WriteHalfWord, address, value as u32)
+    }
+
+    pub fn write_word(&mut self, address: u32, value: u32) -> Result<()> {
+        self.poke(Opcode::WriteWord, address, value)
     }
 
     /// read address of memory and place it into vector.
+    /// uses word and byte reads, so it is meant for small reads like
+    /// registers, bulk reads should use `receive_buffer`.
     pub fn read_memory(&mut self, address: u32, size: u32) -> Result<Vec<u8>> {
-        self.comm.write_all(format!("{:x},w#", address).as_bytes())?;
-        let mut bytes = [0; 4];
-        self.comm.read_exact(&mut bytes)?;
-        Ok(bytes.to_vec())
+        let end = address
+            .checked_add(size)
+            .ok_or(Error::OutOfRange { address, size })?;
+        let mut data = Vec::with_capacity(size as usize);
+        let mut address = address;
+        while address < end {
+            if address.is_multiple_of(4) && end - address >= 4 {
+                data.extend(self.read_word(address)?.to_le_bytes());
+                address += 4;
+            } else {
+                data.push(self.read_byte(address)?);
+                address += 1;
+            }
+        }
+        Ok(data)
+    }
+
+    /// Sends data to memory at `address`, usually an sram buffer.
+    pub fn send_buffer(&mut self, address: u32, data: &[u8]) -> Result<()> {
+        self.send_command(Command::new(Opcode::Send, address, data.len() as u32))?;
+        self.reply_start()?;
+        match self.transport_mode {
+            TransportMode::UsbRaw => self.comm.write_all(data)?,
+            TransportMode::UartXmodem => {
+                let mut xmd = XmdSerial::new();
+                xmd.set_packet_size(self.packet_size);
+                xmd.serial_putdata_xmd(&mut self.comm, data)?
+            }
+        }
+        self.reply_end()
+    }
+
+    /// Receives `size` bytes of memory starting at `address`.
+    pub fn receive_buffer(&mut self, address: u32, size: u32) -> Result<Vec<u8>> {
+        self.send_command(Command::new(Opcode::Receive, address, size))?;
+        self.reply_start()?;
+        let data = match self.transport_mode {
+            TransportMode::UsbRaw => {
+                let mut data = vec![0; size as usize];
+                self.read_exact(&mut data)?;
+                data
+            }
+            TransportMode::UartXmodem => {
+                XmdSerial::new().serial_getdata_xmd(&mut self.comm, size)?
+            }
+        };
+        if data.len() != size as usize {
+            return Err(Error::MalformedReply(data));
+        }
+        self.reply_end()?;
+        Ok(data)
+    }
+
+    /// Jumps to the application whose vector table is at `address`.
+    /// the bootloader is gone after this, so there is no reply to wait for.
+    pub fn go(&mut self, address: u32) -> Result<()> {
+        self.send_command(Command::with_value(Opcode::Go, address))?;
+        self.reply_start()
+    }
+
+    /// Erases flash from `address` to the end of flash.
+    pub fn erase(&mut self, address: u32) -> Result<()> {
+        self.command(Command::with_value(Opcode::Erase, address), b"X\n\r")
+    }
+
+    /// Tells the bootloader where the sram buffer used by 'Y' lives.
+    pub fn set_buffer_address(&mut self, address: u32) -> Result<()> {
+        self.command(Command::new(Opcode::CopyBuffer, address, 0), b"Y\n\r")
+    }
+
+    /// Copies `size` bytes from the sram buffer to flash at `address`.
+    pub fn write_buffer(&mut self, address: u32, size: u32) -> Result<()> {
+        self.command(Command::new(Opcode::CopyBuffer, address, size), b"Y\n\r")
+    }
+
+    /// crc16 of `size` bytes of memory at `address`, computed on the device.
+    pub fn checksum(&mut self, address: u32, size: u32) -> Result<u16> {
+        self.send_command(Command::new(Opcode::Checksum, address, size))?;
+        self.reply_start()?;
+        // "Z" then 8 hex digits then "#\n\r".
+        let mut reply = [0; 12];
+        self.read_exact(&mut reply)?;
+        let crc = match (reply[0], &reply[9..]) {
+            (b'Z', b"#\n\r") => std::str::from_utf8(&reply[1..9])
+                .ok()
+                .and_then(|d| u32::from_str_radix(d, 16).ok())
+                .and_then(|crc| u16::try_from(crc).ok()),
+            _ => None,
+        };
+        let crc = crc.ok_or_else(|| Error::MalformedReply(reply.to_vec()))?;
+        self.reply_end()?;
+        Ok(crc)
     }
 }
 
@@ -63,28 +842,28 @@ where
 #[cfg(test)]
 mod tests {
 
-    use std::time::Duration;
     use crate::arduino::Bootloader;
+    use std::io::Write;
+    use std::time::Duration;
 
+    use super::utils::{BiChannel, LinkModel};
     use super::*;
-    use super::utils::BiChannel;
 
     #[test]
     fn test_read() {
         let channel = BiChannel::new();
         let mut channel_clone = channel.clone();
         channel_clone.set_timeout(Duration::from_secs(2));
-        
+
         let mut bootloader = Bootloader::new(channel);
-        
 
-        let k = std::thread::spawn(|| { 
+        let k = std::thread::spawn(|| {
             let mut arduio_com = ArduinoBootComm::new(channel_clone);
             println!("before read");
             let res = arduio_com.read_memory(0, 4).unwrap();
             println!("faile");
             assert_eq!(res.len(), 4);
-            assert_eq!(&res, &[1,2,3,4]);
+            assert_eq!(&res, &[1, 2, 3, 4]);
             println!("res: {:x?}", res);
         });
         let j = std::thread::spawn(move || {
@@ -101,11 +880,10 @@ mod tests {
         let channel = BiChannel::new();
         let mut channel_clone = channel.clone();
         channel_clone.set_timeout(Duration::from_secs(2));
-        
+
         let mut bootloader = Bootloader::new(channel);
-        
 
-        let k = std::thread::spawn(|| { 
+        let k = std::thread::spawn(|| {
             let mut arduio_com = ArduinoBootComm::new(channel_clone);
             println!("before read");
             let res = arduio_com.read_byte(0).unwrap();
@@ -121,4 +899,436 @@ mod tests {
         k.join().unwrap();
         j.join().unwrap();
     }
+
+    /// Runs a bootloader on its own thread until dropped.
+    struct Emulator {
+        stop: std::sync::Arc<std::sync::atomic::AtomicBool>,
+        handle: Option<std::thread::JoinHandle<()>>,
+    }
+
+    impl Emulator {
+        fn start(mode: TransportMode) -> (Self, ArduinoBootComm<BiChannel>) {
+            Self::with_chip(Chip::Samd21g18, mode)
+        }
+
+        fn with_chip(chip: Chip, mode: TransportMode) -> (Self, ArduinoBootComm<BiChannel>) {
+            Self::with_link(chip, mode, LinkModel::default(), LinkModel::default())
+        }
+
+        /// `host` applies to what the host sends, `device` to the replies.
+        fn with_link(
+            chip: Chip,
+            mode: TransportMode,
+            host: LinkModel,
+            device: LinkModel,
+        ) -> (Self, ArduinoBootComm<BiChannel>) {
+            let mut channel = BiChannel::new();
+            let mut channel_clone = channel.clone();
+            channel.set_link(device);
+            channel_clone.set_link(host);
+            channel_clone.set_timeout(Duration::from_secs(2));
+            // xmodem packets arrive in pieces, so the bootloader has to wait too.
+            channel.set_timeout(Duration::from_millis(100));
+
+            let mut bootloader = Bootloader::with_chip(channel, chip);
+            bootloader.set_transport_mode(mode);
+            let stop = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+            let stop_clone = stop.clone();
+            let handle = std::thread::spawn(move || {
+                while !stop_clone.load(std::sync::atomic::Ordering::Relaxed) {
+                    // rejected commands leave the host waiting, like real hardware.
+                    if let Err(e) = bootloader.update_loop() {
+                        println!("bootloader error: {e}");
+                    }
+                }
+            });
+
+            let mut comm = ArduinoBootComm::new(channel_clone);
+            comm.set_transport_mode(mode);
+            let emulator = Self {
+                stop,
+                handle: Some(handle),
+            };
+            (emulator, comm)
+        }
+    }
+
+    impl Drop for Emulator {
+        fn drop(&mut self) {
+            self.stop.store(true, std::sync::atomic::Ordering::Relaxed);
+            if let Some(handle) = self.handle.take() {
+                // don't hide the real failure behind a second panic.
+                if handle.join().is_err() && !std::thread::panicking() {
+                    panic!("bootloader thread panicked");
+                }
+            }
+        }
+    }
+
+    fn flash_round_trip(mode: TransportMode) {
+        let (_emulator, comm) = Emulator::start(mode);
+        let mut flasher = Flasher::with_device(comm, Chip::Samd21g18.profile());
+        let data: Vec<u8> = (0..5000u32).map(|i| (i * 7) as u8).collect();
+        flasher.erase(0x2000).unwrap();
+        flasher.write(0x2000, &data).unwrap();
+        flasher.verify(0x2000, &data).unwrap();
+        // the tail of the last page is left erased.
+        assert_eq!(flasher.read(0x2000 + 5000, 4).unwrap(), vec![0xff; 4]);
+        assert!(matches!(
+            flasher.verify(0x2000, &[0; 4]),
+            Err(Error::VerifyFailed(0x2001))
+        ));
+    }
+
+    #[test]
+    fn flash_raw() {
+        flash_round_trip(TransportMode::UsbRaw);
+    }
+
+    #[test]
+    fn flash_xmodem() {
+        flash_round_trip(TransportMode::UartXmodem);
+    }
+
+    #[test]
+    fn flash_xmodem_1k() {
+        let (_emulator, mut comm) = Emulator::start(TransportMode::UartXmodem);
+        comm.set_packet_size(PacketSize::OneK);
+        let mut flasher = Flasher::with_device(comm, Chip::Samd21g18.profile());
+        // 1k packets with a short 128 byte tail.
+        let data: Vec<u8> = (0..2100u32).map(|i| (i * 13) as u8).collect();
+        flasher.erase(0x2000).unwrap();
+        flasher.write(0x2000, &data).unwrap();
+        flasher.verify(0x2000, &data).unwrap();
+    }
+
+    #[test]
+    fn peek_poke_all_sizes() {
+        let (_emulator, mut comm) = Emulator::start(TransportMode::UsbRaw);
+        comm.write_word(0x20000000, 0x11223344).unwrap();
+        comm.write_half_word(0x20000004, 0x5566).unwrap();
+        comm.write_byte(0x20000006, 0x77).unwrap();
+        assert_eq!(comm.read_word(0x20000000).unwrap(), 0x11223344);
+        assert_eq!(comm.read_half_word(0x20000004).unwrap(), 0x5566);
+        assert_eq!(comm.read_byte(0x20000006).unwrap(), 0x77);
+        // unaligned reads fall back to bytes.
+        assert_eq!(
+            comm.read_memory(0x20000001, 6).unwrap(),
+            vec![0x33, 0x22, 0x11, 0x66, 0x55, 0x77]
+        );
+    }
+
+    #[test]
+    fn terminal_mode_replies() {
+        let (_emulator, mut comm) = Emulator::start(TransportMode::UsbRaw);
+        comm.write_word(0x20000000, 0xdeadbeef).unwrap();
+        comm.set_terminal_mode().unwrap();
+        assert!(comm.terminal_mode());
+        assert_eq!(comm.read_word(0x20000000).unwrap(), 0xdeadbeef);
+        assert_eq!(comm.read_byte(0x20000000).unwrap(), 0xef);
+        assert!(comm.version().unwrap().starts_with("v2.0"));
+        comm.set_normal_mode().unwrap();
+        assert!(!comm.terminal_mode());
+        assert_eq!(comm.read_half_word(0x20000002).unwrap(), 0xdead);
+        // already binary, still answered.
+        comm.set_normal_mode().unwrap();
+        assert_eq!(comm.read_byte(0x20000003).unwrap(), 0xde);
+    }
+
+    #[test]
+    fn checksum_matches_crc16() {
+        let (_emulator, mut comm) = Emulator::start(TransportMode::UsbRaw);
+        comm.send_buffer(0x20000000, b"123456789").unwrap();
+        assert_eq!(comm.checksum(0x20000000, 9).unwrap(), 0x31c3);
+    }
+
+    #[test]
+    fn timeout_and_malformed_replies() {
+        let mut channel = BiChannel::new();
+        let mut channel_clone = channel.clone();
+        channel_clone.set_timeout(Duration::from_millis(10));
+        let mut comm = ArduinoBootComm::new(channel_clone);
+        assert!(matches!(comm.erase(0x2000), Err(Error::Timeout)));
+
+        channel.write_all(b"Q\n\r").unwrap();
+        assert!(matches!(comm.erase(0x2000), Err(Error::MalformedReply(r)) if r == b"Q\n\r"));
+
+        channel.write_all(b"Z0001FFFF#\n\r").unwrap();
+        assert!(matches!(comm.checksum(0, 4), Err(Error::MalformedReply(_))));
+    }
+
+    #[test]
+    fn slow_link_round_trip() {
+        let link = LinkModel {
+            baud_rate: Some(115200),
+            latency: Duration::from_millis(2),
+            ..Default::default()
+        };
+        let (_emulator, mut comm) =
+            Emulator::with_link(Chip::Samd21g18, TransportMode::UsbRaw, link.clone(), link);
+        comm.send_buffer(0x20000000, b"123456789").unwrap();
+        assert_eq!(comm.checksum(0x20000000, 9).unwrap(), 0x31c3);
+        assert_eq!(comm.read_memory(0x20000000, 4).unwrap(), b"1234");
+    }
+
+    #[test]
+    fn faulty_link_errors() {
+        // every reply byte has a bit flipped.
+        let flips = LinkModel {
+            bit_flip_rate: 1.0,
+            ..Default::default()
+        };
+        let (_emulator, mut comm) = Emulator::with_link(
+            Chip::Samd21g18,
+            TransportMode::UsbRaw,
+            LinkModel::default(),
+            flips,
+        );
+        assert!(matches!(comm.erase(0x2000), Err(Error::MalformedReply(_))));
+
+        // the bootloader never hears the command.
+        let drops = LinkModel {
+            drop_rate: 1.0,
+            ..Default::default()
+        };
+        let (_emulator, mut comm) = Emulator::with_link(
+            Chip::Samd21g18,
+            TransportMode::UsbRaw,
+            drops,
+            LinkModel::default(),
+        );
+        assert!(matches!(comm.erase(0x2000), Err(Error::Timeout)));
+    }
+
+    #[test]
+    fn identify_device() {
+        for chip in Chip::ALL {
+            let (_emulator, comm) = Emulator::with_chip(chip, TransportMode::UsbRaw);
+            let flasher = Flasher::identify(comm).unwrap();
+            assert_eq!(flasher.device().name(), chip.profile().name);
+        }
+
+        let mut channel = BiChannel::new();
+        let mut channel_clone = channel.clone();
+        channel_clone.set_timeout(Duration::from_millis(10));
+        // cpuid of a cortex-m0+ and a did no profile has.
+        channel.write_all(&0x410cc601u32.to_le_bytes()).unwrap();
+        channel.write_all(&0x12345678u32.to_le_bytes()).unwrap();
+        assert!(matches!(
+            Flasher::identify(ArduinoBootComm::new(channel_clone)),
+            Err(Error::UnknownDevice {
+                cpuid: 0x410cc601,
+                id: 0x12345678
+            })
+        ));
+    }
+
+    #[test]
+    fn write_only_changed_rows() {
+        let (_emulator, comm) = Emulator::start(TransportMode::UsbRaw);
+        let mut flasher = Flasher::identify(comm).unwrap();
+        let mut data: Vec<u8> = (0..4000u32).map(|i| (i * 3) as u8).collect();
+        let report = flasher.write_changed(0x2000, &data).unwrap();
+        assert_eq!(report.rows, 16);
+        assert_eq!(report.rows_written, 16);
+        assert_eq!(report.bytes_saved, 0);
+
+        // same image again, nothing to do.
+        let report = flasher.write_changed(0x2000, &data).unwrap();
+        assert_eq!(report.rows_written, 0);
+        assert_eq!(report.bytes_saved, 4000);
+
+        data[300] ^= 0xff;
+        data[3999] ^= 0xff;
+        let report = flasher.write_changed(0x2000, &data).unwrap();
+        assert_eq!(report.rows_written, 2);
+        // the second row and the short last row.
+        assert_eq!(report.bytes_written, 256 + 160);
+        flasher.verify(0x2000, &data).unwrap();
+        assert!(matches!(
+            flasher.write_changed(0x2010, &data),
+            Err(Error::Unaligned(0x2010))
+        ));
+    }
+
+    #[test]
+    fn write_changed_without_row_erase() {
+        let (_emulator, comm) = Emulator::with_chip(Chip::Sam3x8e, TransportMode::UsbRaw);
+        let mut flasher = Flasher::identify(comm).unwrap();
+        let mut data = vec![0x5a; 2048];
+        flasher.write_changed(0, &data).unwrap();
+
+        data[1100] = 0;
+        let report = flasher.write_changed(0, &data).unwrap();
+        // everything from the fifth row on is rewritten.
+        assert_eq!(report.rows_written, 4);
+        assert_eq!(report.bytes_saved, 1024);
+        flasher.verify(0, &data).unwrap();
+
+        // flash after the image does not survive, and the report says so.
+        flasher.write(0x1000, &[0x11; 256]).unwrap();
+        data[0] = 0;
+        let report = flasher.write_changed(0, &data).unwrap();
+        assert_eq!(
+            report.bytes_erased_past,
+            flasher.device().flash_size() - 2048
+        );
+        assert_eq!(flasher.read(0x1000, 4).unwrap(), vec![0xff; 4]);
+        flasher.verify(0, &data).unwrap();
+
+        // nothing changed, nothing erased.
+        let report = flasher.write_changed(0, &data).unwrap();
+        assert_eq!(report.bytes_erased_past, 0);
+    }
+
+    #[test]
+    fn out_of_range_offsets() {
+        let (_emulator, comm) = Emulator::start(TransportMode::UsbRaw);
+        let mut flasher = Flasher::identify(comm).unwrap();
+        let end = flasher.device().flash_size();
+        assert!(matches!(
+            flasher.erase(end + 0x100),
+            Err(Error::OutOfRange { .. })
+        ));
+        assert!(matches!(
+            flasher.write(end - 4, &[0; 8]),
+            Err(Error::OutOfRange { .. })
+        ));
+        assert!(matches!(
+            flasher.write_changed(end, &[0; 8]),
+            Err(Error::OutOfRange { .. })
+        ));
+        assert!(matches!(
+            flasher.read(0x2000, u32::MAX),
+            Err(Error::OutOfRange { .. })
+        ));
+        assert!(matches!(
+            flasher.verify(end, &[0]),
+            Err(Error::OutOfRange { .. })
+        ));
+        assert!(matches!(
+            flasher.comm().read_memory(0xfffffffc, 8),
+            Err(Error::OutOfRange { .. })
+        ));
+        // nothing was sent for those, the link is still in sync.
+        assert_eq!(flasher.read(end - 4, 4).unwrap(), vec![0xff; 4]);
+    }
+
+    #[test]
+    fn boot_flash_needs_an_eefc() {
+        let (_emulator, comm) = Emulator::with_chip(Chip::Sam3x8e, TransportMode::UsbRaw);
+        let mut flasher = Flasher::identify(comm).unwrap();
+        flasher.set_boot_flash().unwrap();
+        assert_eq!(flasher.comm().read_word(0x400e0a04).unwrap(), 0x5a00010b);
+
+        let (_emulator, comm) = Emulator::start(TransportMode::UsbRaw);
+        let mut flasher = Flasher::identify(comm).unwrap();
+        assert!(matches!(
+            flasher.set_boot_flash(),
+            Err(Error::Unsupported(_))
+        ));
+    }
+
+    #[test]
+    fn progress_reports() {
+        let (_emulator, comm) = Emulator::start(TransportMode::UsbRaw);
+        let mut flasher = Flasher::identify(comm).unwrap();
+        let updates = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        let updates_clone = updates.clone();
+        flasher.set_progress(Box::new(move |phase, done, total| {
+            updates_clone.lock().unwrap().push((phase, done, total));
+        }));
+
+        let data = vec![0x42; 10000];
+        flasher.write(0x2000, &data).unwrap();
+        flasher.verify(0x2000, &data).unwrap();
+        assert_eq!(
+            *updates.lock().unwrap(),
+            vec![
+                (Phase::Write, 0, 10000),
+                (Phase::Write, 4096, 10000),
+                (Phase::Write, 8192, 10000),
+                (Phase::Write, 10000, 10000),
+                (Phase::Verify, 0, 10000),
+                (Phase::Verify, 4096, 10000),
+                (Phase::Verify, 8192, 10000),
+                (Phase::Verify, 10000, 10000),
+            ]
+        );
+    }
+
+    #[test]
+    fn cancel_between_pages() {
+        let (_emulator, comm) = Emulator::start(TransportMode::UsbRaw);
+        let mut flasher = Flasher::identify(comm).unwrap();
+        let cancel = CancelToken::new();
+        let cancel_clone = cancel.clone();
+        flasher.set_cancel_token(cancel.clone());
+        // cancel once the second buffer is on its way.
+        flasher.set_progress(Box::new(move |phase, done, _| {
+            if phase == Phase::Write && done > 0 {
+                cancel_clone.cancel();
+            }
+        }));
+
+        flasher.erase(0x2000).unwrap();
+        let data = vec![0x42; 10000];
+        assert!(matches!(
+            flasher.write(0x2000, &data),
+            Err(Error::Cancelled)
+        ));
+        assert!(cancel.is_cancelled());
+        assert!(matches!(flasher.read(0x2000, 4), Err(Error::Cancelled)));
+
+        flasher.set_cancel_token(CancelToken::new());
+        flasher.set_progress(Box::new(|_, _, _| {}));
+        // the buffer in flight when it was cancelled still finishes.
+        assert_eq!(flasher.read(0x2000 + 8191, 2).unwrap(), vec![0x42, 0xff]);
+    }
+
+    #[test]
+    fn bootloader_needs_force() {
+        let (_emulator, comm) = Emulator::start(TransportMode::UsbRaw);
+        let mut flasher = Flasher::identify(comm).unwrap();
+        assert!(matches!(flasher.erase(0), Err(Error::BootloaderRegion(0))));
+        assert!(matches!(
+            flasher.write(0x1f00, &[0; 4]),
+            Err(Error::BootloaderRegion(0x1f00))
+        ));
+        assert!(matches!(
+            flasher.write_changed(0x1f00, &[0; 4]),
+            Err(Error::BootloaderRegion(0x1f00))
+        ));
+
+        // forced through, the BOOTPROT fuse still keeps the row intact.
+        flasher.set_force(true);
+        assert!(matches!(
+            flasher.write(0x1f00, &[0; 4]),
+            Err(Error::Protected(0x1f00))
+        ));
+        assert!(matches!(
+            flasher.write_changed(0x1f00, &[0; 4]),
+            Err(Error::Protected(0x1f00))
+        ));
+        assert_eq!(flasher.read(0x1f00, 4).unwrap(), vec![0xff; 4]);
+        // the flag was cleared, the next write goes through.
+        flasher.write(0x2000, &[0; 4]).unwrap();
+    }
+
+    #[test]
+    fn touch_emulator_pty() {
+        let port = crate::arduino::VirtualPort::open(None).unwrap();
+        let name = port.slave_path();
+        let mut bootloader = Bootloader::new(port);
+        let events = bootloader.subscribe();
+
+        // the pty is not a usb board, so its name is kept.
+        let finder = PortFinder::new();
+        assert_eq!(
+            enter_bootloader(&name, &finder, Duration::from_secs(1)).unwrap(),
+            name
+        );
+        bootloader.update_loop().unwrap();
+        assert_eq!(events.try_recv().unwrap(), crate::arduino::Event::Reset);
+    }
 }
@@ -1,22 +1,99 @@
+use crate::arduino::LineState;
 use std::{
     collections::VecDeque,
     sync::{Arc, Mutex}, time::{Duration, Instant},
 };
 
+/// Timing and faults applied to the bytes one end of a `BiChannel`
+/// writes, so protocol code can be tested against a realistic line.
+/// the default is a perfect, instant link.
+#[derive(Debug, Clone, Default)]
+pub struct LinkModel {
+    /// paces bytes at 10 bits each (8n1), None sends them instantly.
+    pub baud_rate: Option<u32>,
+    /// added to every byte once it is on the line.
+    pub latency: Duration,
+    /// chance per byte of a single bit flipping.
+    pub bit_flip_rate: f64,
+    /// chance per byte of it never arriving.
+    pub drop_rate: f64,
+    /// chance per byte of it arriving twice.
+    pub duplicate_rate: f64,
+    /// (bytes written, duration), the line goes quiet for duration
+    /// before that byte is sent.
+    pub stalls: Vec<(usize, Duration)>,
+    /// seed for the faults, the same seed gives the same faults.
+    pub seed: u64,
+}
+
+impl LinkModel {
+    /// time one byte takes on the line.
+    fn byte_time(&self) -> Duration {
+        match self.baud_rate {
+            Some(baud) => Duration::from_secs_f64(10.0 / baud as f64),
+            None => Duration::ZERO,
+        }
+    }
+}
+
+/// xorshift64*, enough to make faults repeatable without a dependency.
+#[derive(Debug, Clone)]
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // xorshift gets stuck at zero.
+        Self(if seed == 0 { 0x9e3779b97f4a7c15 } else { seed })
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545f4914f6cdd1d)
+    }
+
+    fn chance(&mut self, rate: f64) -> bool {
+        // top 53 bits as a float in [0, 1).
+        let sample = (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64;
+        rate > 0.0 && sample < rate
+    }
+}
+
+// a byte and the moment the reader may see it.
+type Queue = Arc<Mutex<VecDeque<(Instant, u8)>>>;
+
 pub struct BiChannel {
     id: usize,
     // if id == 0,
     // incoming is then data being recived.
     // else it is outgoing.
-    incoming: Arc<Mutex<VecDeque<u8>>>,
+    incoming: Queue,
 
     // if id == 0,
     // out going is data being sent
     // else it is incoming.
-    outgoing: Arc<Mutex<VecDeque<u8>>>,
+    outgoing: Queue,
 
     // timeout for both reading and writing operations. 
     timeout: Duration,
+
+    // baud rate the host end picked, shared by both ends.
+    baud_rate: Arc<Mutex<Option<u32>>>,
+
+    // faults applied to what this end writes.
+    link: LinkModel,
+    rng: Rng,
+    // bytes written through the link so far.
+    written: usize,
+    // when the line is free to send the next byte.
+    line_free: Option<Instant>,
+}
+
+impl Default for BiChannel {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl BiChannel {
@@ -26,12 +103,40 @@ impl BiChannel {
             incoming: Arc::new(Mutex::new(VecDeque::new())),
             outgoing: Arc::new(Mutex::new(VecDeque::new())),
             timeout: Duration::from_secs(0),
+            baud_rate: Arc::new(Mutex::new(None)),
+            link: LinkModel::default(),
+            rng: Rng::new(0),
+            written: 0,
+            line_free: None,
         }
     }
 
     pub fn set_timeout(&mut self, timeout: Duration) {
         self.timeout = timeout;
     }
+
+    /// Acts like the host reopening the port at `baud_rate`.
+    pub fn set_baud_rate(&mut self, baud_rate: u32) {
+        *self.baud_rate.lock().unwrap() = Some(baud_rate);
+    }
+
+    /// Applies `link` to everything this end writes from now on.
+    pub fn set_link(&mut self, link: LinkModel) {
+        self.rng = Rng::new(link.seed);
+        self.link = link;
+        self.written = 0;
+        self.line_free = None;
+    }
+
+    pub fn link(&self) -> &LinkModel {
+        &self.link
+    }
+}
+
+impl LineState for BiChannel {
+    fn baud_rate(&self) -> Option<u32> {
+        *self.baud_rate.lock().unwrap()
+    }
 }
 
 impl Clone for BiChannel {
@@ -44,18 +149,19 @@ impl Clone for BiChannel {
             incoming: self.incoming.clone(),
             outgoing: self.outgoing.clone(),
             timeout: self.timeout,
+            baud_rate: self.baud_rate.clone(),
+            link: self.link.clone(),
+            rng: Rng::new(self.link.seed),
+            written: 0,
+            line_free: None,
         }
     }
 }
 
 impl std::io::Read for BiChannel {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        // noteably this implimentation, sort of just checks
-        // if any bytes are available on timeout
-        // not really sure what read_exact does if this produces
-        // a timeout without reading all requested bytes
-        // do we get multiple timeouts? seems strange from
-        // the read_exact perspective. 
+        // returns whatever has arrived once there is at least one byte,
+        // like a serial port it gives up with TimedOut after the timeout.
         let start_timeout = Instant::now();
         loop { 
             let mut read_source = if self.id == 0 {
@@ -64,47 +170,79 @@ impl std::io::Read for BiChannel {
                 self.outgoing.lock().unwrap()
             };
 
-            let read_count = if read_source.len() > buf.len() {
-                buf.len() 
-            } else {
-                read_source.len()
-            };
+            // bytes still on the line are not there yet.
+            let now = Instant::now();
+            let mut read_count = 0;
+            while read_count < buf.len() {
+                match read_source.front() {
+                    Some(&(ready, byte)) if ready <= now => {
+                        buf[read_count] = byte;
+                        read_source.pop_front();
+                        read_count += 1;
+                    }
+                    _ => break,
+                }
+            }
             println!("Read count: {}, timeout: {}", read_count, self.timeout.as_secs());
             if read_count == 0 {
                 if start_timeout.elapsed() < self.timeout {
+                    drop(read_source);
                     // give a bit of time to allow the writer to gain the lock. 
                     std::thread::sleep(Duration::from_millis(1));
                     continue;
                 } else {
-                    return Ok(0);
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        "no bytes before the timeout",
+                    ));
                 }
             }
-            
-            for i in 0..read_count {
-                buf[i] = read_source.pop_front().ok_or(std::io::Error::new(
-                    std::io::ErrorKind::UnexpectedEof,
-                    "out of bytes to read",
-                ))?;
-            }
-            return Ok(buf.len());
+            return Ok(read_count);
         }
     }
 }
 
 impl std::io::Write for BiChannel {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        if self.id == 0 {
-            let mut v = self.outgoing.lock().unwrap();
-            for i in buf.iter() {
-                v.push_back(*i);
-            }
+        let mut v = if self.id == 0 {
+            self.outgoing.lock().unwrap()
         } else {
-
-            let mut v = self.incoming.lock().unwrap();
-            for i in buf.iter() {
+            self.incoming.lock().unwrap()
+        };
+        let byte_time = self.link.byte_time();
+        for i in buf.iter() {
+            if self.id != 0 {
                 println!("Writing: {}", i);
-                v.push_back(*i);
             }
+            let now = Instant::now();
+            let mut sent = self.line_free.map_or(now, |free| free.max(now));
+            for (at, duration) in self.link.stalls.iter() {
+                if *at == self.written {
+                    sent += *duration;
+                }
+            }
+            self.written += 1;
+
+            let mut byte = *i;
+            if self.rng.chance(self.link.bit_flip_rate) {
+                byte ^= 1 << (self.rng.next_u64() % 8);
+            }
+            let copies = if self.rng.chance(self.link.drop_rate) {
+                0
+            } else if self.rng.chance(self.link.duplicate_rate) {
+                2
+            } else {
+                1
+            };
+            // a dropped byte still took its time on the line.
+            sent += byte_time;
+            for copy in 0..copies {
+                if copy > 0 {
+                    sent += byte_time;
+                }
+                v.push_back((sent + self.link.latency, byte));
+            }
+            self.line_free = Some(sent);
         }
         Ok(buf.len())
     }
@@ -145,4 +283,104 @@ mod tests {
         assert_eq!(k, 10);
         assert_eq!(&b, &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9]);
     }
+
+    #[test]
+    fn empty_read_times_out() {
+        let mut bi_channel = BiChannel::new();
+        bi_channel.set_timeout(Duration::from_millis(10));
+        let mut b = [0; 4];
+        let err = bi_channel.read(&mut b).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn baud_rate_and_latency_pacing() {
+        let mut bi_channel = BiChannel::new();
+        let mut bi_clone_channel = bi_channel.clone();
+        bi_clone_channel.set_timeout(Duration::from_secs(2));
+        // 100 bytes at 10000 baud take 100ms.
+        bi_channel.set_link(LinkModel {
+            baud_rate: Some(10000),
+            latency: Duration::from_millis(20),
+            ..Default::default()
+        });
+        let start = Instant::now();
+        bi_channel.write_all(&[0x55; 100]).unwrap();
+        let mut b = [0; 100];
+        bi_clone_channel.read_exact(&mut b).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(120));
+        assert_eq!(b, [0x55; 100]);
+    }
+
+    fn corrupt(link: LinkModel, data: &[u8]) -> Vec<u8> {
+        let mut bi_channel = BiChannel::new();
+        let mut bi_clone_channel = bi_channel.clone();
+        bi_channel.set_link(link);
+        bi_channel.write_all(data).unwrap();
+        let mut out = Vec::new();
+        let mut b = [0; 64];
+        while let Ok(n) = bi_clone_channel.read(&mut b) {
+            out.extend_from_slice(&b[..n]);
+        }
+        out
+    }
+
+    #[test]
+    fn seeded_faults_repeat() {
+        let data: Vec<u8> = (0..200).map(|i| i as u8).collect();
+        let link = LinkModel {
+            bit_flip_rate: 0.1,
+            drop_rate: 0.05,
+            duplicate_rate: 0.05,
+            seed: 42,
+            ..Default::default()
+        };
+        let first = corrupt(link.clone(), &data);
+        assert_ne!(first, data);
+        assert_eq!(corrupt(link.clone(), &data), first);
+        assert_ne!(corrupt(LinkModel { seed: 7, ..link }, &data), first);
+
+        let flipped = corrupt(
+            LinkModel {
+                bit_flip_rate: 1.0,
+                ..Default::default()
+            },
+            &data,
+        );
+        assert!(flipped
+            .iter()
+            .zip(&data)
+            .all(|(a, b)| (a ^ b).count_ones() == 1));
+        let dropped = LinkModel {
+            drop_rate: 1.0,
+            ..Default::default()
+        };
+        assert!(corrupt(dropped, &data).is_empty());
+        let doubled = LinkModel {
+            duplicate_rate: 1.0,
+            ..Default::default()
+        };
+        assert_eq!(corrupt(doubled, &[1, 2]), vec![1, 1, 2, 2]);
+    }
+
+    #[test]
+    fn stall_times_out_reader() {
+        let mut bi_channel = BiChannel::new();
+        let mut bi_clone_channel = bi_channel.clone();
+        bi_clone_channel.set_timeout(Duration::from_millis(50));
+        bi_channel.set_link(LinkModel {
+            stalls: vec![(4, Duration::from_millis(300))],
+            ..Default::default()
+        });
+        bi_channel.write_all(&[0, 1, 2, 3, 4, 5, 6, 7]).unwrap();
+        let mut b = [0; 8];
+        assert_eq!(bi_clone_channel.read(&mut b).unwrap(), 4);
+        let err = bi_clone_channel.read(&mut b).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+
+        // the rest shows up once the stall is over.
+        bi_clone_channel.set_timeout(Duration::from_secs(2));
+        bi_clone_channel.read_exact(&mut b[..4]).unwrap();
+        assert_eq!(&b[..4], &[4, 5, 6, 7]);
+    }
 }
@@ -8,14 +8,25 @@
 /// the end goal currently is to implement my own flashing utility for
 /// flash the arduino samd21 bootloader and then build a second stage bootloader
 /// to write down the knowledge I have over elf file formats.
-/// use the following command to create a virtual serial device
-/// `socat -d -d pty,rawer,echo=0 pty,rawer,echo=0`
+/// main serves the bootloader on its own pty and links the slave side
+/// to `/tmp/borg-<chip>`, point bossac's `-p` at that link.
+/// when poking at it by hand with screen or picocom send `T#` first
+/// so replies are printed as hex, `N#` switches back to binary.
 
 pub type Result<T> = core::result::Result<T, Error>;
 
+pub use chip::Chip;
+pub use pty::VirtualPort;
+pub use samba::{Command, Opcode, SambaParser};
+
+mod chip;
 mod flash;
-mod flash_utility;
-mod xmd_serial;
+pub mod flash_utility;
+mod nvmctrl;
+pub mod port_finder;
+mod pty;
+pub mod samba;
+pub mod xmd_serial;
 
 #[derive(thiserror::Error)]
 pub enum Error {
@@ -27,8 +38,20 @@ pub enum Error {
     #[error("Flash overlap")]
     FlashOverLap,
 
+    #[error("Invalid flash image: {0}")]
+    InvalidImage(&'static str),
+
     #[error("Xmodem communication error: {0}")]
     XModem(xmd_serial::Error),
+
+    #[error("Serial port error: {0}")]
+    SerialPort(serialport::Error),
+
+    #[error("Flash row {0:x} is protected")]
+    FlashProtected(u32),
+
+    #[error("Unknown command {:?}(0x{0:02x})", *.0 as char)]
+    UnknownCommand(u8),
 }
 
 impl std::fmt::Debug for Error {
@@ -43,208 +66,614 @@ impl From<std::io::Error> for Error {
     }
 }
 
+impl From<serialport::Error> for Error {
+    fn from(value: serialport::Error) -> Self {
+        Self::SerialPort(value)
+    }
+}
+
 impl From<xmd_serial::Error> for Error {
     fn from(value: xmd_serial::Error) -> Self {
         Self::XModem(value)
     }
 }
 
+/// How bulk data for 'S' and 'R' moves over the link.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportMode {
+    /// usb cdc, data is sent as raw bytes.
+    UsbRaw,
+    /// uart, data is framed with xmodem.
+    UartXmodem,
+}
+
+/// Where the flash contents of the mocked device are kept between runs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageOptions {
+    pub path: std::path::PathBuf,
+    /// restore the image when it is attached, if the file exists.
+    pub load_on_start: bool,
+    /// snapshot the image when the bootloader is dropped.
+    pub save_on_exit: bool,
+    /// snapshot the image after every command that changes flash, for
+    /// when the process may be killed without running destructors.
+    pub save_on_change: bool,
+}
+
+/// What the mocked device is currently running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Bootloader,
+    Application(JumpTarget),
+}
+
+/// Where a 'G' command sent the cpu.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JumpTarget {
+    /// address of the vector table.
+    pub address: u32,
+    pub stack_pointer: u32,
+    pub reset_vector: u32,
+}
+
+/// Things that happened on the mocked device that callers may care about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// the bootloader handed off to an application.
+    Jump(JumpTarget),
+    /// a 'G' command pointed at a vector table that would crash the cpu.
+    JumpRejected(JumpTarget),
+    /// the host opened the port at 1200 baud and the device restarted
+    /// into the bootloader.
+    Reset,
+}
+
+/// Links that know the baud rate the host opened them with, arduino
+/// boards watch it to notice the 1200 baud touch.
+pub trait LineState {
+    /// None if the link has no notion of a baud rate.
+    fn baud_rate(&self) -> Option<u32>;
+}
+
+// opening the port at this rate asks the board to reset into the bootloader.
+pub const TOUCH_BAUD: u32 = 1200;
+
+// the most a single 'S' takes, hosts send a buffer of a few pages at a time.
+const MAX_TRANSFER: u32 = 0x10000;
+
+/// Behaviour attached to the application once the bootloader has jumped to it.
+pub trait Application: Send {
+    fn start(&mut self, _target: &JumpTarget) {}
+
+    /// Called with bytes received while the application is running,
+    /// whatever is returned is sent back.
+    fn receive(&mut self, data: &[u8]) -> Vec<u8>;
+}
+
 // arduino side bootloader mock implementation.
 pub struct Bootloader<T> {
     comm_inter: T,
-    ptr_data: u32,
-    command: u8,
-    current_number: u32,
+    parser: SambaParser,
     src_buff_addr: u32,
     terminal_mode: bool,
+    transport_mode: TransportMode,
     version_str: &'static str,
     attempt: u32,
 
+    chip: &'static chip::ChipProfile,
     flash: flash::Flash,
+    nvmctrl: Option<nvmctrl::Nvmctrl>,
+
+    image: Option<ImageOptions>,
+    state: State,
+    application: Option<Box<dyn Application>>,
+    subscribers: Vec<std::sync::mpsc::Sender<Event>>,
+    // last baud rate seen on the link.
+    baud_rate: Option<u32>,
+    // return unknown commands as errors instead of ignoring them.
+    strict: bool,
 }
 
 impl<T> Bootloader<T>
 where
-    T: std::io::Read + std::io::Write,
+    T: std::io::Read + std::io::Write + LineState,
 {
     pub fn new(comm_inter: T) -> Self {
+        Self::with_chip(comm_inter, Chip::Samd21g18)
+    }
+
+    /// Creates a bootloader whose memory map and id registers
+    /// match the given chip.
+    pub fn with_chip(comm_inter: T, chip: Chip) -> Self {
         let version_str = "v2.0 [Arduino:XYZ] Apr 19 2019 14:38:48";
+        let chip = chip.profile();
         let mut flash = flash::Flash::default();
-        // reverse pulled addresses
-        // todo: look at the samd21g memory space for legit ranges.
-        flash.add_block(0x0, 0x300).unwrap();
-        flash.add_block(0x2000, 0x20000).unwrap();
-        flash.add_block(0xe000ed00, 0x300).unwrap();
-        flash.add_block(0x400e0740, 0x300).unwrap();
-        flash.add_block(0x41004020, 0x300).unwrap();
-        flash.add_block(0x40000834, 0x300).unwrap();
-        // mock out the chip id.
-        flash.write(0x4, &0x10010005_u32.to_le_bytes()).unwrap();
-        flash
-            .write(0xe000ed00, &0x10010005_u32.to_le_bytes())
-            .unwrap();
-        flash
-            .write(0x400e0740, &0x10010005_u32.to_le_bytes())
-            .unwrap();
-        flash.add_block(0x20004000, 0x2000).unwrap();
+        flash.add_block(chip.flash.start, chip.flash.size).unwrap();
+        flash.add_block(chip.sram.start, chip.sram.size).unwrap();
+        for range in chip.peripherals {
+            flash.add_block(range.start, range.size).unwrap();
+        }
+        for reg in chip.id_registers {
+            flash.write(reg.address, &reg.value.to_le_bytes()).unwrap();
+        }
 
-        flash.write(0, &[1,2,3,4]);
-            
+        // commands on the flash controller finish at once, it always reads ready.
+        if let Some(eefc) = chip.eefc {
+            let ready = chip::EEFC_FSR_FRDY.to_le_bytes();
+            flash.write(eefc + chip::EEFC_FSR, &ready).unwrap();
+        }
 
+        flash.write(0, &[1, 2, 3, 4]).unwrap();
+        let nvmctrl = chip.nvmctrl.map(|base| {
+            let mut nvm = nvmctrl::Nvmctrl::new(base, chip);
+            nvm.add_user_row(&mut flash).unwrap();
+            nvm.load_fuses(&mut flash).unwrap();
+            nvm
+        });
+
+        let baud_rate = comm_inter.baud_rate();
         Self {
             attempt: 0,
             comm_inter,
-            ptr_data: 0,
-            command: 0,
+            parser: SambaParser::new(),
             src_buff_addr: 0,
-            current_number: 0,
             terminal_mode: false,
+            transport_mode: TransportMode::UartXmodem,
             version_str,
+            chip,
             flash,
+            nvmctrl,
+            image: None,
+            state: State::Bootloader,
+            application: None,
+            subscribers: Vec::new(),
+            baud_rate,
+            strict: false,
+        }
+    }
+
+    /// Pick the framing used for bulk transfers, defaults to xmodem
+    /// which is what bossac uses when it does not see a usb port.
+    pub fn set_transport_mode(&mut self, mode: TransportMode) {
+        self.transport_mode = mode;
+    }
+
+    /// In strict mode unknown commands are returned as
+    /// `Error::UnknownCommand`, otherwise they are logged and dropped
+    /// like the real firmware does.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// Attach a file backed image to the flash.
+    pub fn set_image(&mut self, options: ImageOptions) -> Result<()> {
+        if options.load_on_start && options.path.exists() {
+            println!("Loading flash image {}", options.path.display());
+            self.flash.load_file(&options.path)?;
+            // the image carries its own fuses.
+            if let Some(nvm) = self.nvmctrl.as_mut() {
+                nvm.load_fuses(&mut self.flash)?;
+            }
+        }
+        self.image = Some(options);
+        Ok(())
+    }
+
+    /// Writes the flash out to the attached image, if there is one.
+    pub fn save_image(&self) -> Result<()> {
+        if let Some(options) = &self.image {
+            println!("Saving flash image {}", options.path.display());
+            self.flash.save_file(&options.path)?;
         }
+        Ok(())
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// Attach behaviour for when the bootloader jumps to the application.
+    pub fn set_application(&mut self, application: Box<dyn Application>) {
+        self.application = Some(application);
+    }
+
+    /// Returns a receiver for events, events are only sent to
+    /// receivers subscribed before they happen.
+    pub fn subscribe(&mut self) -> std::sync::mpsc::Receiver<Event> {
+        let (tx, rx) = std::sync::mpsc::channel();
+        self.subscribers.push(tx);
+        rx
+    }
+
+    fn emit(&mut self, event: Event) {
+        println!("Event: {:x?}", event);
+        // drop subscribers that went away.
+        self.subscribers.retain(|tx| tx.send(event).is_ok());
+    }
+
+    /// Resets the cpu back into the bootloader, like a real board
+    /// the fuses are loaded again.
+    pub fn restart(&mut self) -> Result<()> {
+        println!("Restarting into the bootloader");
+        self.state = State::Bootloader;
+        self.parser.reset();
+        self.terminal_mode = false;
+        if let Some(nvm) = self.nvmctrl.as_mut() {
+            nvm.load_fuses(&mut self.flash)?;
+        }
+        self.emit(Event::Reset);
+        Ok(())
+    }
+
+    /// Restarts if the host just opened the link at 1200 baud.
+    fn check_touch(&mut self) -> Result<()> {
+        let baud_rate = self.comm_inter.baud_rate();
+        let touched = baud_rate == Some(TOUCH_BAUD) && self.baud_rate != baud_rate;
+        self.baud_rate = baud_rate;
+        if touched {
+            self.restart()?;
+        }
+        Ok(())
     }
 
     pub fn update_loop(&mut self) -> Result<()> {
+        self.check_touch()?;
         // read from serial chunk.
         let mut data_chunk = [0xff; 64];
         println!("Attempt: {:?}", self.attempt);
         let length = match self.comm_inter.read(&mut data_chunk) {
             Ok(r) => r,
-            Err(f) => {
-                // todo: filter by timeout error.
+            // nothing arrived, try again next time.
+            Err(f)
+                if matches!(
+                    f.kind(),
+                    std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock
+                ) =>
+            {
                 return Ok(());
             }
+            Err(f) => {
+                println!("comm iter read error: {f}");
+                return Err(f.into());
+            }
         };
-        // .inspect_err(|f| println!("comm iter read error: {f}"))?;
-        let k: Vec<char> = data_chunk.iter().map(|f| *f as char).collect();
-        println!("Data chunk: {:x?}", k);
-        let mut index = 0;
-        let mut j: u8 = 0;
-        println!("Length: {}", length);
-        while index < length {
-            println!(
-                "Index: {}  Command: {}(0x{:02x}) Ptr data: {:x}",
-                index, self.command as char, self.command, self.ptr_data
-            );
-            if data_chunk[index] == 0xff {
-                index += 1;
-                continue;
+        if let State::Application(_) = self.state {
+            return self.application_receive(&data_chunk[..length]);
+        }
+        println!("Data chunk: {:x?}", &data_chunk[..length]);
+        let mut data = &data_chunk[..length];
+        while !data.is_empty() {
+            let (used, command) = self.parser.parse(data);
+            data = &data[used..];
+            let Some(command) = command else {
+                break;
+            };
+            let used = self.execute(command, data)?;
+            data = &data[used..];
+        }
+        Ok(())
+    }
+
+    /// Runs one command, `rest` is what was received after its '#'.
+    /// returns how much of `rest` the command used up.
+    fn execute(&mut self, command: Command, rest: &[u8]) -> Result<usize> {
+        println!(
+            "Process {} address {:x} value {:x}",
+            command.opcode.byte() as char,
+            command.address,
+            command.value
+        );
+        let (address, value) = (command.address, command.value);
+        let mut used = 0;
+        // set by the commands that changed flash.
+        let mut changed = false;
+        if self.terminal_mode {
+            self.comm_inter.write_all(b"\n\r")?;
+        }
+        match command.opcode {
+            Opcode::Send => {
+                // a protected range is still taken off the link, the
+                // payload would otherwise be parsed as commands.
+                let protected = self.check_protected(address, value);
+                // part of the data may already be in this chunk.
+                let inline = rest.len().min(value as usize);
+                let mut data = rest[..inline].to_vec();
+                used = inline;
+                if (inline as u32) < value {
+                    let tail = address.saturating_add(inline as u32);
+                    data.extend(self.receive_data(tail, value - inline as u32)?);
+                }
+                protected?;
+                // flash is written through the nvm controller, like 'W'.
+                changed = self.write_memory(address, &data)?;
             }
-            if data_chunk[index] == b'#' {
-                println!(
-                    "Process {} current numb {:x} length {} index {}",
-                    self.command as char, self.current_number, length, index
-                );
-                if self.command == b'S' {
-                    if length > index {
-                        index += 1;
-
-                        let u32tmp = if (length - index) < self.current_number as usize {
-                            length - index
-                        } else {
-                            self.current_number as usize
-                        };
-
-                        self.flash
-                            .write(self.ptr_data, &data_chunk[index..index + u32tmp])?;
-                        index += u32tmp;
-                        j = u32tmp as u8;
-                    }
-                    index -= 1;
-
-                    if (j as u32) < self.current_number {
-                        let mut s = xmd_serial::XmdSerial::new();
-                        let data = s.serial_getdata_xmd(
-                            &mut self.comm_inter,
-                            self.current_number - j as u32,
-                        )?;
-                        self.flash.write(self.ptr_data, &data)?;
-                    }
-                } else if self.command == b'W' {
-                    self.ptr_data = self.current_number;
-                } else if self.command == b'o' {
-                    let data = self.flash.read(self.ptr_data, 1)?;
-                    self.comm_inter.write_all(&data);
-                } else if self.command == b'N' {
-                    if self.terminal_mode {
-                        self.comm_inter.write_all(b"\n\r")?;
-                    }
-                    self.terminal_mode = false;
-                } else if self.command == b'w' {
-                    self.current_number = self.ptr_data;
-                    let d = self.flash.read(self.current_number, 4)?;
-                    self.comm_inter.write_all(&d)?;
-                } else if self.command == b'V' {
-                    // note the 'v' is important.
-                    self.comm_inter.write_all(self.version_str.as_bytes())?;
+            Opcode::Receive => {
+                let data = self.read_memory(address, value)?;
+                // the receiver may have started its handshake already,
+                // those bytes are in this chunk and belong to the transfer.
+                self.send_data(&data, rest)?;
+                used = rest.len();
+            }
+            Opcode::WriteByte => changed = self.write_memory(address, &[value as u8])?,
+            Opcode::WriteHalfWord => {
+                changed = self.write_memory(address, &(value as u16).to_le_bytes())?
+            }
+            Opcode::WriteWord => changed = self.write_memory(address, &value.to_le_bytes())?,
+            Opcode::ReadByte => self.peek(address, 1)?,
+            Opcode::ReadHalfWord => self.peek(address, 2)?,
+            Opcode::ReadWord => self.peek(address, 4)?,
+            Opcode::NormalMode => {
+                // in terminal mode the "\n\r" above was the reply.
+                if !self.terminal_mode {
                     self.comm_inter.write_all(b"\n\r")?;
-                    self.attempt += 1;
-                } else if self.command == b'X' {
-                    self.erase_flash(self.current_number);
-                    // oddly enough the bossa continue even if
-                    // we don't send a response.
-                    self.comm_inter.write_all(b"X\n\r")?;
-                } else if self.command == b'Y' {
-                    if self.current_number == 0 {
-                        println!("Setting src buffer addr: {:x}", self.ptr_data);
-                        self.src_buff_addr = self.ptr_data;
-                    } else {
-                        // todo: why divide by page size?
-                        let size = self.current_number / 4;
-                        let data = self
-                            .flash
-                            .read(self.src_buff_addr, size)
-                            .inspect_err(|f| println!("flash read error: {f}"))?;
-                        let dst_addr = self.ptr_data;
-                        println!(
-                            "Updating flash with sram {:x}({}) to {:x}",
-                            self.src_buff_addr, size, dst_addr
-                        );
-                        self.flash
-                            .write(dst_addr, &data)
-                            .inspect_err(|f| println!("flash write error: {f}"))?;
-                    }
-                    println!("Send response to w/e");
-                    self.comm_inter
-                        .write_all(b"Y\n\r")
-                        .inspect_err(|f| println!("got error: {f}"))?;
-                    println!("finished sending response");
-                } else {
-                    if self.command == 0 || self.command == 0x80 {
-                    } else {
-                        todo!()
-                    }
                 }
-            } else {
-                if (b'0' <= data_chunk[index]) && (data_chunk[index] <= b'9') {
-                    self.current_number =
-                        self.current_number << 4 | (data_chunk[index] - b'0') as u32;
-                } else if (b'A' <= data_chunk[index]) && (data_chunk[index] <= b'F') {
-                    self.current_number =
-                        self.current_number << 4 | (data_chunk[index] - b'A' + 0xa) as u32;
-                } else if (b'a' <= data_chunk[index]) && (data_chunk[index] <= b'f') {
-                    self.current_number =
-                        self.current_number << 4 | (data_chunk[index] - b'a' + 0xa) as u32;
-                } else if data_chunk[index] == b',' {
-                    // ptr data is like index, in that
-                    // it points to an address.
-                    // however it does not point to data_chunk, but
-                    // rather a ram/flash address of sorts.
-                    self.ptr_data = self.current_number;
-                    self.current_number = 0;
+                self.terminal_mode = false;
+            }
+            Opcode::TerminalMode => {
+                self.terminal_mode = true;
+                self.comm_inter.write_all(b"\n\r")?;
+            }
+            Opcode::Version => {
+                // note the 'v' is important.
+                self.comm_inter.write_all(self.version_str.as_bytes())?;
+                self.comm_inter.write_all(b"\n\r")?;
+                self.attempt += 1;
+            }
+            Opcode::Erase => {
+                // like the firmware the reply goes out even when the erase
+                // was rejected, the host finds out from the nvm status.
+                let erased = self.erase_flash(value);
+                self.comm_inter.write_all(b"X\n\r")?;
+                erased?;
+                changed = true;
+            }
+            Opcode::Checksum => {
+                let data = self.read_memory(address, value)?;
+                let crc = xmd_serial::crc16(&data);
+                self.comm_inter
+                    .write_all(format!("Z{:08X}#\n\r", crc).as_bytes())?;
+            }
+            Opcode::Go => {
+                if self.jump(value)? {
+                    // the rest of the chunk belongs to the application.
+                    self.application_receive(rest)?;
+                    return Ok(rest.len());
+                }
+            }
+            Opcode::CopyBuffer => {
+                let copied = if value == 0 {
+                    println!("Setting src buffer addr: {:x}", address);
+                    self.src_buff_addr = address;
+                    Ok(false)
                 } else {
-                    self.command = data_chunk[index];
-                    self.current_number = 0;
+                    self.copy_buffer(address, value).map(|_| true)
+                };
+                // a rejected copy is still answered, like 'X'.
+                println!("Send response to w/e");
+                self.comm_inter
+                    .write_all(b"Y\n\r")
+                    .inspect_err(|f| println!("got error: {f}"))?;
+                println!("finished sending response");
+                changed = copied?;
+            }
+            Opcode::Unknown(byte) => {
+                println!("Unknown command {}(0x{:02x})", byte as char, byte);
+                if self.strict {
+                    return Err(Error::UnknownCommand(byte));
                 }
+                // the firmware drops it without a reply, only the
+                // terminal prompt below still goes out.
             }
-            index += 1;
+        }
+        if changed && self.image.as_ref().is_some_and(|image| image.save_on_change) {
+            self.save_image()?;
+        }
+        if self.terminal_mode {
+            self.comm_inter.write_all(b">")?;
+        }
+        Ok(used)
+    }
+
+    /// Loads the stack pointer and reset vector from the vector table
+    /// at `address`, if both look sane the application starts running.
+    fn jump(&mut self, address: u32) -> Result<bool> {
+        let table = self.read_memory(address, 8)?;
+        let target = JumpTarget {
+            address,
+            stack_pointer: u32::from_le_bytes([table[0], table[1], table[2], table[3]]),
+            reset_vector: u32::from_le_bytes([table[4], table[5], table[6], table[7]]),
+        };
+        // the stack grows down so the initial value may be the end of sram,
+        // the reset vector has the thumb bit set.
+        let sram = self.chip.sram;
+        let stack_ok = target.stack_pointer > sram.start && target.stack_pointer <= sram.end();
+        let entry_ok = self.chip.flash.contains(target.reset_vector & !1);
+        if !stack_ok || !entry_ok {
+            self.emit(Event::JumpRejected(target));
+            return Ok(false);
+        }
+
+        self.state = State::Application(target);
+        if let Some(app) = &mut self.application {
+            app.start(&target);
+        }
+        self.emit(Event::Jump(target));
+        Ok(true)
+    }
+
+    fn application_receive(&mut self, data: &[u8]) -> Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        if let Some(app) = &mut self.application {
+            let reply = app.receive(data);
+            self.comm_inter.write_all(&reply)?;
+        }
+        Ok(())
+    }
+
+    /// Receives the data of a 'S' command that did not fit in the command chunk.
+    /// the range is checked first so a bad length never gets allocated.
+    fn receive_data(&mut self, address: u32, length: u32) -> Result<Vec<u8>> {
+        if length > MAX_TRANSFER || !self.flash.contains(address, length) {
+            return Err(Error::FlashOutOfBounds(address, length));
+        }
+        match self.transport_mode {
+            TransportMode::UsbRaw => {
+                let mut data = vec![0; length as usize];
+                self.comm_inter.read_exact(&mut data)?;
+                Ok(data)
+            }
+            TransportMode::UartXmodem => {
+                let mut s = xmd_serial::XmdSerial::new();
+                Ok(s.serial_getdata_xmd(&mut self.comm_inter, length)?)
+            }
+        }
+    }
+
+    /// Sends the data of a 'R' command.
+    fn send_data(&mut self, data: &[u8], pending: &[u8]) -> Result<()> {
+        match self.transport_mode {
+            TransportMode::UsbRaw => self.comm_inter.write_all(data)?,
+            TransportMode::UartXmodem => {
+                let mut comm = Pending {
+                    pending,
+                    comm: &mut self.comm_inter,
+                };
+                let mut s = xmd_serial::XmdSerial::new();
+                s.serial_putdata_xmd(&mut comm, data)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends back the `size` bytes at `address`, little endian like the device,
+    /// in terminal mode the value is printed as hex instead.
+    fn peek(&mut self, address: u32, size: u32) -> Result<()> {
+        let data = self.read_memory(address, size)?;
+        if self.terminal_mode {
+            let value = data.iter().rev().fold(0, |value, d| value << 8 | *d as u32);
+            let text = format!("0x{:0width$X}\n\r", value, width = size as usize * 2);
+            self.comm_inter.write_all(text.as_bytes())?;
+        } else {
+            self.comm_inter.write_all(&data)?;
         }
         Ok(())
     }
 
-    fn erase_flash(&mut self, dst_addr: u32) {
+    /// Reads memory as seen from the cpu, registers of mocked
+    /// peripherals are served by the peripheral.
+    fn read_memory(&mut self, address: u32, length: u32) -> Result<Vec<u8>> {
+        match &self.nvmctrl {
+            Some(nvm) if nvm.holds_read(address, length) => Ok(nvm.read(address, length)),
+            Some(nvm) if nvm.is_register(address) => Err(Error::FlashOutOfBounds(address, length)),
+            _ => self.flash.read(address, length),
+        }
+    }
+
+    /// Writes memory as seen from the cpu, with a nvm controller
+    /// present writes to flash go through its page buffer.
+    /// returns true if flash changed.
+    fn write_memory(&mut self, address: u32, data: &[u8]) -> Result<bool> {
+        match &mut self.nvmctrl {
+            Some(nvm) if nvm.is_register(address) => nvm.write(address, data, &mut self.flash),
+            Some(nvm) if nvm.is_flash(address) => nvm.write_flash(address, data, &mut self.flash),
+            _ => {
+                self.flash.write(address, data)?;
+                Ok(self.chip.flash.contains(address))
+            }
+        }
+    }
+
+    /// Copies `length` bytes from the sram buffer to flash at `address`.
+    fn copy_buffer(&mut self, address: u32, length: u32) -> Result<()> {
+        // the firmware copies length / 4 words.
+        let data = self
+            .flash
+            .read(self.src_buff_addr, length)
+            .inspect_err(|f| println!("flash read error: {f}"))?;
+        self.check_protected(address, length)?;
+        println!(
+            "Updating flash with sram {:x}({}) to {:x}",
+            self.src_buff_addr, length, address
+        );
+        self.flash
+            .write(address, &data)
+            .inspect_err(|f| println!("flash write error: {f}"))
+    }
+
+    /// Fails if BOOTPROT or a lock region covers part of the range, the
+    /// nvm controller then flags a lock error the host can read back.
+    fn check_protected(&mut self, address: u32, length: u32) -> Result<()> {
+        let Some(nvm) = self.nvmctrl.as_mut() else {
+            return Ok(());
+        };
+        match nvm.protected_row(address, length) {
+            Some(row) => {
+                nvm.set_lock_error();
+                Err(Error::FlashProtected(row))
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Erases from the row containing `dst_addr` to the end of flash.
+    /// like the real bootloader a whole row is erased even if the
+    /// address points into the middle of it, rows belonging to the
+    /// bootloader itself are left alone.
+    fn erase_flash(&mut self, dst_addr: u32) -> Result<()> {
         println!("Erase flash: {:x}", dst_addr);
-        // todo
+        let row_size = self.chip.row_size;
+        let mut row_addr = (dst_addr & !(row_size - 1)).max(self.chip.app_start());
+        if row_addr >= self.chip.flash.end() {
+            // past the end of flash, there is nothing to erase.
+            return Ok(());
+        }
+        // nothing is erased if a locked row is in the way.
+        self.check_protected(row_addr, self.chip.flash.end() - row_addr)?;
+        while row_addr < self.chip.flash.end() {
+            self.flash.erase(row_addr, row_size)?;
+            row_addr += row_size;
+        }
+        Ok(())
+    }
+}
+
+/// Hands out bytes already taken off the link before reading from it again.
+struct Pending<'a, T> {
+    pending: &'a [u8],
+    comm: &'a mut T,
+}
+
+impl<T: std::io::Read> std::io::Read for Pending<'_, T> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.pending.is_empty() {
+            self.comm.read(buf)
+        } else {
+            std::io::Read::read(&mut self.pending, buf)
+        }
+    }
+}
+
+impl<T: std::io::Write> std::io::Write for Pending<'_, T> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.comm.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.comm.flush()
+    }
+}
+
+impl<T> Drop for Bootloader<T> {
+    fn drop(&mut self) {
+        if let Some(options) = &self.image {
+            if options.save_on_exit {
+                if let Err(e) = self.flash.save_file(&options.path) {
+                    println!("Failed to save flash image: {e}");
+                }
+            }
+        }
     }
 }
 
@@ -252,22 +681,403 @@ where
 mod test {
     // tests use dummy ttys
 
+    use std::io::{Read, Write};
     use std::time::Duration;
 
-    use super::Bootloader;
+    use super::flash_utility::utils::BiChannel;
+    use super::{
+        Application, Bootloader, Chip, Error, Event, ImageOptions, JumpTarget, State,
+        TransportMode,
+    };
+
+    /// returns a bootloader and the host end of the channel it serves.
+    fn bootloader_pair() -> (Bootloader<BiChannel>, BiChannel) {
+        let channel = BiChannel::new();
+        let mut host = channel.clone();
+        host.set_timeout(Duration::from_secs(2));
+        (Bootloader::new(channel), host)
+    }
+
+    #[test]
+    fn erase_flash_rows() {
+        let (mut bootloader, mut host) = bootloader_pair();
+        for addr in [0x1000, 0x2000, 0x2100, 0x3fffc] {
+            bootloader.flash.write(addr, &[0xaa; 4]).unwrap();
+        }
+
+        // starts in the middle of the row at 0x2000.
+        host.write_all(b"X2080#").unwrap();
+        bootloader.update_loop().unwrap();
+        let mut buf = [0; 3];
+        host.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"X\n\r");
+
+        assert_eq!(bootloader.flash.read(0x1000, 4).unwrap(), vec![0xaa; 4]);
+        for addr in [0x2000, 0x2100, 0x3fffc] {
+            assert_eq!(bootloader.flash.read(addr, 4).unwrap(), vec![0xff; 4]);
+        }
+    }
 
     #[test]
-    fn write_buffer() {
-        let port_name = "/dev/pts/5";
-        let mut port = serialport::new(port_name, 9600)
-            .open()
-            .expect("Failed to open serial port");
-        port.set_timeout(Duration::from_millis(40000))
-            .expect("Failed to set timeout");
-        port.clear(serialport::ClearBuffer::Input)
-            .expect("failed to clear buffer");
+    fn chip_id_registers() {
+        for (chip, cmd, id) in [
+            (Chip::Samd21g18, b"w41002018,4#", 0x10010005_u32),
+            (Chip::Samd21e18, b"w41002018,4#", 0x1001000a),
+            (Chip::Samd51j19, b"w41002018,4#", 0x60060005),
+            (Chip::Sam3x8e, b"w400e0940,4#", 0x285e0a60),
+        ] {
+            let channel = BiChannel::new();
+            let mut host = channel.clone();
+            host.set_timeout(Duration::from_secs(2));
+            let mut bootloader = Bootloader::with_chip(channel, chip);
+
+            host.write_all(cmd).unwrap();
+            bootloader.update_loop().unwrap();
+            let mut buf = [0; 4];
+            host.read_exact(&mut buf).unwrap();
+            assert_eq!(u32::from_le_bytes(buf), id, "{:?}", chip);
+        }
+    }
+
+    #[test]
+    fn nvmctrl_page_write() {
+        let (mut bootloader, mut host) = bootloader_pair();
+        // same sequence bossac uses, manual write mode, clear the
+        // page buffer, fill it, then write page at ADDR.
+        host.write_all(b"W41004004,80#").unwrap();
+        host.write_all(b"W41004000,A544#").unwrap();
+        host.write_all(b"W2000,04030201#").unwrap();
+        host.write_all(b"W2004,08070605#").unwrap();
+        bootloader.update_loop().unwrap();
+        assert_eq!(bootloader.flash.read(0x2000, 4).unwrap(), vec![0xff; 4]);
+
+        host.write_all(b"W4100401C,1000#").unwrap();
+        host.write_all(b"W41004000,A504#").unwrap();
+        host.write_all(b"w41004014,4#").unwrap();
+        bootloader.update_loop().unwrap();
+        let mut buf = [0; 4];
+        host.read_exact(&mut buf).unwrap();
+        assert_eq!(buf[0] & 0x1, 1, "nvmctrl not ready");
+        assert_eq!(
+            bootloader.flash.read(0x2000, 8).unwrap(),
+            vec![1, 2, 3, 4, 5, 6, 7, 8]
+        );
+    }
+
+    #[test]
+    fn peek_poke() {
+        let (mut bootloader, mut host) = bootloader_pair();
+        host.write_all(b"W20000000,11223344#").unwrap();
+        host.write_all(b"H20000004,5566#").unwrap();
+        host.write_all(b"O20000006,77#").unwrap();
+        bootloader.update_loop().unwrap();
+        assert_eq!(
+            bootloader.flash.read(0x20000000, 8).unwrap(),
+            vec![0x44, 0x33, 0x22, 0x11, 0x66, 0x55, 0x77, 0xff]
+        );
+
+        host.write_all(b"w20000000,4#h20000004,2#o20000006,1#")
+            .unwrap();
+        bootloader.update_loop().unwrap();
+        let mut buf = [0; 7];
+        host.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [0x44, 0x33, 0x22, 0x11, 0x66, 0x55, 0x77]);
+    }
+
+    #[test]
+    fn terminal_mode() {
+        let (mut bootloader, mut host) = bootloader_pair();
+        bootloader
+            .flash
+            .write(0x20000000, &[0x44, 0x33, 0x22, 0x11])
+            .unwrap();
+
+        host.write_all(b"T#").unwrap();
+        bootloader.update_loop().unwrap();
+        let mut buf = [0; 3];
+        host.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"\n\r>");
+
+        host.write_all(b"w20000000,4#o20000001,1#").unwrap();
+        bootloader.update_loop().unwrap();
+        let mut buf = [0; 24];
+        host.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"\n\r0x11223344\n\r>\n\r0x33\n\r>");
+
+        // back to binary, no prompt afterwards.
+        host.write_all(b"N#o20000001,1#").unwrap();
+        bootloader.update_loop().unwrap();
+        let mut buf = [0; 3];
+        host.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"\n\r\x33");
+
+        // answered the same way when already binary.
+        host.write_all(b"N#").unwrap();
+        bootloader.update_loop().unwrap();
+        let mut buf = [0; 2];
+        host.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"\n\r");
+    }
+
+    #[test]
+    fn send_raw() {
+        let (mut bootloader, mut host) = bootloader_pair();
+        bootloader.set_transport_mode(TransportMode::UsbRaw);
+        let data: Vec<u8> = (0..0x80).collect();
+
+        // more data than fits in the first chunk.
+        host.write_all(b"S20000000,80#").unwrap();
+        host.write_all(&data).unwrap();
+        bootloader.update_loop().unwrap();
+        assert_eq!(bootloader.flash.read(0x20000000, 0x80).unwrap(), data);
+    }
+
+    #[test]
+    fn send_xmodem() {
+        let channel = BiChannel::new();
+        let mut host = channel.clone();
+        host.set_timeout(Duration::from_secs(2));
+        let mut bootloader_channel = channel;
+        bootloader_channel.set_timeout(Duration::from_secs(2));
+        let mut bootloader = Bootloader::new(bootloader_channel);
+        let data: Vec<u8> = (0..0x80).collect();
+
+        let packet = data.clone();
+        let h = std::thread::spawn(move || {
+            host.write_all(b"S20000000,80#").unwrap();
+            let mut buf = [0; 1];
+            host.read_exact(&mut buf).unwrap();
+            assert_eq!(buf[0], b'C');
+            host.write_all(&[0x01, 1, 0xfe]).unwrap();
+            host.write_all(&packet).unwrap();
+            host.write_all(&super::xmd_serial::crc16(&packet).to_be_bytes())
+                .unwrap();
+            host.read_exact(&mut buf).unwrap();
+            assert_eq!(buf[0], 0x06);
+            // end of transmission.
+            host.write_all(&[0x04]).unwrap();
+            host.read_exact(&mut buf).unwrap();
+            assert_eq!(buf[0], 0x06);
+        });
+        bootloader.update_loop().unwrap();
+        h.join().unwrap();
+        assert_eq!(bootloader.flash.read(0x20000000, 0x80).unwrap(), data);
+    }
+
+    #[test]
+    fn receive_raw() {
+        let (mut bootloader, mut host) = bootloader_pair();
+        bootloader.set_transport_mode(TransportMode::UsbRaw);
+        let data: Vec<u8> = (0..0x80).collect();
+        bootloader.flash.write(0x2000, &data).unwrap();
+
+        host.write_all(b"R2000,80#").unwrap();
+        bootloader.update_loop().unwrap();
+        let mut buf = [0; 0x80];
+        host.read_exact(&mut buf).unwrap();
+        assert_eq!(buf.to_vec(), data);
+    }
+
+    #[test]
+    fn receive_xmodem() {
+        let channel = BiChannel::new();
+        let mut host = channel.clone();
+        host.set_timeout(Duration::from_secs(2));
+        let mut bootloader_channel = channel;
+        bootloader_channel.set_timeout(Duration::from_secs(2));
+        let mut bootloader = Bootloader::new(bootloader_channel);
+        let data: Vec<u8> = (0..0x100).map(|i| i as u8).collect();
+        bootloader.flash.write(0x2000, &data).unwrap();
+
+        let expected = data.clone();
+        let h = std::thread::spawn(move || {
+            host.write_all(b"R2000,100#").unwrap();
+            let mut s = super::xmd_serial::XmdSerial::new();
+            let r = s.serial_getdata_xmd(&mut host, 0x100).unwrap();
+            assert_eq!(r, expected);
+        });
+        bootloader.update_loop().unwrap();
+        h.join().unwrap();
+    }
+
+    #[test]
+    fn image_survives_restart() {
+        let path = std::env::temp_dir().join(format!("borg-image-{}.bin", std::process::id()));
+        let options = ImageOptions {
+            path: path.clone(),
+            load_on_start: true,
+            save_on_exit: true,
+            save_on_change: false,
+        };
+        {
+            let (mut bootloader, _host) = bootloader_pair();
+            bootloader.set_image(options.clone()).unwrap();
+            bootloader.flash.write(0x2000, &[1, 2, 3, 4]).unwrap();
+        }
+        let (mut bootloader, _host) = bootloader_pair();
+        bootloader.set_image(options).unwrap();
+        assert_eq!(bootloader.flash.read(0x2000, 4).unwrap(), vec![1, 2, 3, 4]);
+        // dropping it saves again, so it has to go first.
+        drop(bootloader);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn image_saved_on_change() {
+        let path = std::env::temp_dir().join(format!("borg-change-{}.bin", std::process::id()));
+        let (mut bootloader, mut host) = bootloader_pair();
+        bootloader
+            .set_image(ImageOptions {
+                path: path.clone(),
+                load_on_start: false,
+                save_on_exit: false,
+                save_on_change: true,
+            })
+            .unwrap();
+
+        // sram only, nothing to save yet.
+        host.write_all(b"S20000000,4#\x01\x02\x03\x04").unwrap();
+        bootloader.update_loop().unwrap();
+        assert!(!path.exists());
+
+        host.write_all(b"Y20000000,0#Y2000,100#").unwrap();
+        bootloader.update_loop().unwrap();
+        let mut buf = [0; 6];
+        host.read_exact(&mut buf).unwrap();
+        // read back without dropping the bootloader first.
+        let restore = || {
+            let mut restored = Bootloader::new(BiChannel::new());
+            restored
+                .set_image(ImageOptions {
+                    path: path.clone(),
+                    load_on_start: true,
+                    save_on_exit: false,
+                    save_on_change: false,
+                })
+                .unwrap();
+            restored
+        };
+        assert_eq!(restore().flash.read(0x2000, 4).unwrap(), vec![1, 2, 3, 4]);
+        std::fs::remove_file(&path).unwrap();
+
+        // in automatic mode the page is written with its last word.
+        for i in 0..16 {
+            assert!(!path.exists(), "saved before the page was full");
+            host.write_all(format!("W{:X},AABBCCDD#", 0x2040 + i * 4).as_bytes()).unwrap();
+            bootloader.update_loop().unwrap();
+        }
+        assert_eq!(restore().flash.read(0x2040, 4).unwrap(), vec![0xdd, 0xcc, 0xbb, 0xaa]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn checksum() {
+        let (mut bootloader, mut host) = bootloader_pair();
+        bootloader.flash.write(0x2000, b"123456789").unwrap();
+
+        host.write_all(b"Z2000,9#").unwrap();
+        bootloader.update_loop().unwrap();
+        let mut buf = [0; 12];
+        host.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"Z000031C3#\n\r");
+    }
+
+    struct Echo;
 
-        let r_port = serialport::new("/dev/pts/6", 9600)
+    impl Application for Echo {
+        fn receive(&mut self, data: &[u8]) -> Vec<u8> {
+            data.to_vec()
+        }
+    }
+
+    #[test]
+    fn jump_to_application() {
+        let (mut bootloader, mut host) = bootloader_pair();
+        bootloader.set_application(Box::new(Echo));
+        let events = bootloader.subscribe();
+        let mut vectors = 0x20008000_u32.to_le_bytes().to_vec();
+        vectors.extend(0x21a1_u32.to_le_bytes());
+        bootloader.flash.write(0x2000, &vectors).unwrap();
+
+        host.write_all(b"G2000#hi").unwrap();
+        bootloader.update_loop().unwrap();
+        let target = JumpTarget {
+            address: 0x2000,
+            stack_pointer: 0x20008000,
+            reset_vector: 0x21a1,
+        };
+        assert_eq!(bootloader.state(), State::Application(target));
+        assert_eq!(events.try_recv().unwrap(), Event::Jump(target));
+
+        // bootloader commands no longer apply.
+        host.write_all(b"V#").unwrap();
+        bootloader.update_loop().unwrap();
+        let mut buf = [0; 4];
+        host.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hiV#");
+    }
+
+    #[test]
+    fn jump_rejected() {
+        let (mut bootloader, mut host) = bootloader_pair();
+        let events = bootloader.subscribe();
+
+        // erased flash does not hold a usable vector table.
+        host.write_all(b"G2000#").unwrap();
+        bootloader.update_loop().unwrap();
+        assert_eq!(bootloader.state(), State::Bootloader);
+        assert_eq!(
+            events.try_recv().unwrap(),
+            Event::JumpRejected(JumpTarget {
+                address: 0x2000,
+                stack_pointer: 0xffffffff,
+                reset_vector: 0xffffffff,
+            })
+        );
+    }
+
+    #[test]
+    fn erase_flash_keeps_bootloader() {
+        let (mut bootloader, mut host) = bootloader_pair();
+        bootloader.flash.write(0x100, &[0xaa; 4]).unwrap();
+        bootloader.flash.write(0x2000, &[0xaa; 4]).unwrap();
+
+        host.write_all(b"X0#").unwrap();
+        bootloader.update_loop().unwrap();
+        let mut buf = [0; 3];
+        host.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"X\n\r");
+
+        assert_eq!(bootloader.flash.read(0x100, 4).unwrap(), vec![0xaa; 4]);
+        assert_eq!(bootloader.flash.read(0x2000, 4).unwrap(), vec![0xff; 4]);
+    }
+
+    #[test]
+    fn erase_past_flash_end() {
+        for (chip, cmd) in [
+            (Chip::Samd21g18, b"X20000000#"),
+            (Chip::Sam3x8e, b"X20070000#"),
+        ] {
+            let channel = BiChannel::new();
+            let mut host = channel.clone();
+            host.set_timeout(Duration::from_secs(2));
+            let mut bootloader = Bootloader::with_chip(channel, chip);
+            let app_start = bootloader.chip.app_start();
+            bootloader.flash.write(app_start, &[0xaa; 4]).unwrap();
+
+            host.write_all(cmd).unwrap();
+            bootloader.update_loop().unwrap();
+            let mut buf = [0; 3];
+            host.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf, b"X\n\r", "{:?}", chip);
+            assert_eq!(bootloader.flash.read(app_start, 4).unwrap(), vec![0xaa; 4]);
+        }
+    }
+
+    #[test]
+    fn write_buffer() {
+        let r_port = super::VirtualPort::open(None).unwrap();
+        let mut port = serialport::new(r_port.slave_path(), 9600)
             .open()
             .expect("Failed to open serial port");
         port.set_timeout(Duration::from_millis(40000))
@@ -277,7 +1087,8 @@ mod test {
 
         let j = std::thread::spawn(|| {
             let mut bootloader = Bootloader::new(r_port);
-            loop {
+            // each command takes a couple of reads at most.
+            for _ in 0..20 {
                 bootloader.update_loop().expect("failed bootloader loop");
             }
         });
@@ -293,7 +1104,221 @@ mod test {
         port.read_exact(&mut buf).unwrap();
         assert_eq!(buf[0], b'Y');
 
-        j.join();
-        assert!(false);
+        j.join().unwrap();
+    }
+
+    #[test]
+    fn protected_rows_are_rejected() {
+        let (mut bootloader, mut host) = bootloader_pair();
+        bootloader.flash.write(0x20000000, &[0; 256]).unwrap();
+        // STATUS.LOCKE, the host reads it back after a write or erase.
+        let locke = |bootloader: &mut Bootloader<BiChannel>, host: &mut BiChannel| {
+            host.write_all(b"h41004018,2#").unwrap();
+            bootloader.update_loop().unwrap();
+            let mut status = [0; 2];
+            host.read_exact(&mut status).unwrap();
+            // cleared by writing a one.
+            host.write_all(b"H41004018,8#").unwrap();
+            bootloader.update_loop().unwrap();
+            status[0] & 0x8 != 0
+        };
+
+        // copying into the bootloader fails, the reply still goes out.
+        host.write_all(b"Y20000000,0#").unwrap();
+        bootloader.update_loop().unwrap();
+        let mut buf = [0; 3];
+        host.read_exact(&mut buf).unwrap();
+        host.write_all(b"Y1F00,100#").unwrap();
+        assert!(matches!(
+            bootloader.update_loop(),
+            Err(Error::FlashProtected(0x1f00))
+        ));
+        host.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"Y\n\r");
+        assert_eq!(bootloader.flash.read(0x1f00, 4).unwrap(), vec![0xff; 4]);
+        assert!(locke(&mut bootloader, &mut host));
+        assert!(!locke(&mut bootloader, &mut host));
+
+        // ranges outside the main array are not protected, and the one
+        // wrapping past the top of the address space is out of bounds.
+        host.write_all(b"Y20000000,0#YFFFFFF00,100#").unwrap();
+        assert!(matches!(
+            bootloader.update_loop(),
+            Err(Error::FlashOutOfBounds(0xffffff00, 0x100))
+        ));
+        let mut replies = [0; 6];
+        host.read_exact(&mut replies).unwrap();
+        assert!(!locke(&mut bootloader, &mut host));
+
+        // lock the region holding 0x8000, 'X' now stops before erasing.
+        bootloader.flash.write(0x8000, &[0; 4]).unwrap();
+        host.write_all(b"W4100401C,4000#W41004000,A540#").unwrap();
+        bootloader.update_loop().unwrap();
+        host.write_all(b"X2000#").unwrap();
+        assert!(matches!(
+            bootloader.update_loop(),
+            Err(Error::FlashProtected(0x8000))
+        ));
+        host.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"X\n\r");
+        assert_eq!(bootloader.flash.read(0x8000, 4).unwrap(), vec![0; 4]);
+        assert!(locke(&mut bootloader, &mut host));
+
+        // the bootloader keeps serving after a rejected command.
+        host.write_all(b"Y2000,100#").unwrap();
+        bootloader.update_loop().unwrap();
+        host.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"Y\n\r");
+        assert_eq!(bootloader.flash.read(0x2000, 4).unwrap(), vec![0; 4]);
+    }
+
+    #[test]
+    fn send_into_bootloader_is_rejected() {
+        let (mut bootloader, mut host) = bootloader_pair();
+        bootloader.set_transport_mode(TransportMode::UsbRaw);
+        bootloader.flash.write(0x100, &[0xaa; 4]).unwrap();
+
+        host.write_all(b"S100,4#\x01\x02\x03\x04").unwrap();
+        assert!(matches!(
+            bootloader.update_loop(),
+            Err(Error::FlashProtected(0x100))
+        ));
+        assert_eq!(bootloader.flash.read(0x100, 4).unwrap(), vec![0xaa; 4]);
+
+        // a whole page into the application lands once the buffer is full.
+        host.write_all(b"S2000,40#").unwrap();
+        host.write_all(&[0x55; 0x40]).unwrap();
+        bootloader.update_loop().unwrap();
+        assert_eq!(bootloader.flash.read(0x2000, 0x40).unwrap(), vec![0x55; 0x40]);
+    }
+
+    #[test]
+    fn oversized_sends_are_rejected() {
+        let (mut bootloader, mut host) = bootloader_pair();
+        bootloader.set_transport_mode(TransportMode::UsbRaw);
+        for cmd in [&b"S20000000,FFFFFFF0#"[..], b"S10000000,10#", b"S0,20000#"] {
+            host.write_all(cmd).unwrap();
+            assert!(matches!(
+                bootloader.update_loop(),
+                Err(Error::FlashOutOfBounds(..))
+            ));
+        }
+        host.write_all(b"V#").unwrap();
+        bootloader.update_loop().unwrap();
+        let mut buf = [0; 4];
+        host.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"v2.0");
+    }
+
+    #[test]
+    fn touch_restarts_into_bootloader() {
+        let (mut bootloader, mut host) = bootloader_pair();
+        bootloader.set_application(Box::new(Echo));
+        let events = bootloader.subscribe();
+        let mut vectors = 0x20008000_u32.to_le_bytes().to_vec();
+        vectors.extend(0x21a1_u32.to_le_bytes());
+        bootloader.flash.write(0x2000, &vectors).unwrap();
+        host.write_all(b"G2000#").unwrap();
+        bootloader.update_loop().unwrap();
+        assert!(matches!(events.try_recv().unwrap(), Event::Jump(_)));
+
+        // a normal open does nothing.
+        host.set_baud_rate(115200);
+        bootloader.update_loop().unwrap();
+        assert!(matches!(bootloader.state(), State::Application(_)));
+
+        host.set_baud_rate(1200);
+        host.write_all(b"V#").unwrap();
+        bootloader.update_loop().unwrap();
+        assert_eq!(bootloader.state(), State::Bootloader);
+        assert_eq!(events.try_recv().unwrap(), Event::Reset);
+        let mut buf = [0; 41];
+        host.read_exact(&mut buf).unwrap();
+        assert!(buf.starts_with(b"v2.0"));
+
+        // staying at 1200 is not another touch.
+        bootloader.update_loop().unwrap();
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn unknown_commands() {
+        let (mut bootloader, mut host) = bootloader_pair();
+        // lenient by default, nothing comes back for the unknown one.
+        host.write_all(b"Q1234#V#").unwrap();
+        bootloader.update_loop().unwrap();
+        let mut buf = [0; 41];
+        host.read_exact(&mut buf).unwrap();
+        assert!(buf.starts_with(b"v2.0"));
+
+        bootloader.set_strict(true);
+        host.write_all(b"q20,4#").unwrap();
+        assert!(matches!(
+            bootloader.update_loop(),
+            Err(Error::UnknownCommand(b'q'))
+        ));
+        // the parser starts over after the error.
+        host.write_all(b"w0,4#").unwrap();
+        bootloader.update_loop().unwrap();
+        let mut buf = [0; 4];
+        host.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn oversized_reads_are_rejected() {
+        let (mut bootloader, mut host) = bootloader_pair();
+        // the end of these ranges wraps past the top of the address space.
+        for cmd in [&b"Z10,FFFFFFFF#"[..], b"R10,FFFFFFFF#", b"R41004000,FFFFFFFF#"] {
+            host.write_all(cmd).unwrap();
+            assert!(matches!(
+                bootloader.update_loop(),
+                Err(Error::FlashOutOfBounds(_, 0xffffffff))
+            ));
+        }
+        // still serving.
+        host.write_all(b"Z10,4#").unwrap();
+        bootloader.update_loop().unwrap();
+        let mut buf = [0; 12];
+        host.read_exact(&mut buf).unwrap();
+        assert!(buf.starts_with(b"Z"));
+    }
+
+    /// a link that has gone away.
+    struct Unplugged;
+
+    impl Read for Unplugged {
+        fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+            Err(std::io::ErrorKind::BrokenPipe.into())
+        }
+    }
+
+    impl Write for Unplugged {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl super::LineState for Unplugged {
+        fn baud_rate(&self) -> Option<u32> {
+            None
+        }
+    }
+
+    #[test]
+    fn read_errors_propagate() {
+        // a quiet link is not an error.
+        let (mut bootloader, _host) = bootloader_pair();
+        bootloader.update_loop().unwrap();
+
+        let mut bootloader = Bootloader::new(Unplugged);
+        assert!(matches!(
+            bootloader.update_loop(),
+            Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::BrokenPipe
+        ));
     }
 }
@@ -0,0 +1,439 @@
+/// Mock of the samd21 non volatile memory controller.
+/// bossac drives this through 'W' pokes when it programs a part,
+/// writes to the flash address space are collected in the page buffer
+/// and only land in flash once a write page command is issued.
+/// the BOOTPROT fuse and lock bits are loaded from the user row when the
+/// chip starts, rows they cover can not be erased or written.
+use super::chip::ChipProfile;
+use super::flash::Flash;
+use super::Result;
+
+// register offsets from the nvmctrl base.
+const CTRLA: u32 = 0x00;
+const CTRLB: u32 = 0x04;
+const PARAM: u32 = 0x08;
+const INTFLAG: u32 = 0x14;
+const STATUS: u32 = 0x18;
+const ADDR: u32 = 0x1c;
+const LOCK: u32 = 0x20;
+const REG_SPACE: u32 = 0x24;
+
+const CMDEX_KEY: u16 = 0xa5;
+
+/// the user row, the fuses the controller loads at reset live here.
+pub const USER_ROW: u32 = 0x804000;
+// arduino ships its boards with an 8k BOOTPROT and every region unlocked.
+const USER_ROW_DEFAULT: [u32; 2] = [0xd8e0c7fa, 0xfffffc5d];
+// BOOTPROT is the low three bits of the first word, 7 means no protection.
+const BOOTPROT_MASK: u32 = 0x7;
+const BOOTPROT_NONE: u32 = 0x7;
+
+// commands written to CTRLA.CMD
+const CMD_ER: u16 = 0x02;
+const CMD_WP: u16 = 0x04;
+const CMD_EAR: u16 = 0x05;
+const CMD_WAP: u16 = 0x06;
+const CMD_LR: u16 = 0x40;
+const CMD_UR: u16 = 0x41;
+const CMD_PBC: u16 = 0x44;
+
+const INTFLAG_READY: u8 = 1 << 0;
+const INTFLAG_ERROR: u8 = 1 << 1;
+
+const STATUS_LOAD: u16 = 1 << 1;
+const STATUS_PROGE: u16 = 1 << 2;
+const STATUS_LOCKE: u16 = 1 << 3;
+
+const CTRLB_MANW: u32 = 1 << 7;
+
+// flash is split into this many lock regions.
+const LOCK_REGIONS: u32 = 16;
+
+pub struct Nvmctrl {
+    base: u32,
+    flash_start: u32,
+    flash_size: u32,
+    page_size: u32,
+    row_size: u32,
+
+    ctrlb: u32,
+    intflag: u8,
+    status: u16,
+    addr: u32,
+    // a cleared bit means the region is locked.
+    lock: u16,
+    // bytes at the start of flash protected by BOOTPROT.
+    bootprot: u32,
+
+    page_buffer: Vec<u8>,
+    // page the buffer was last loaded for.
+    buffer_page: u32,
+}
+
+impl Nvmctrl {
+    pub fn new(base: u32, chip: &ChipProfile) -> Self {
+        Self {
+            base,
+            flash_start: chip.flash.start,
+            flash_size: chip.flash.size,
+            page_size: chip.page_size,
+            row_size: chip.row_size,
+            ctrlb: 0,
+            intflag: INTFLAG_READY,
+            status: 0,
+            addr: 0,
+            lock: 0xffff,
+            bootprot: 0,
+            page_buffer: vec![0xff; chip.page_size as usize],
+            buffer_page: 0,
+        }
+    }
+
+    /// true if the address is one of the nvmctrl registers.
+    pub fn is_register(&self, address: u32) -> bool {
+        address >= self.base && address < self.base + REG_SPACE
+    }
+
+    /// true if a read of length bytes at address stays in the registers.
+    pub fn holds_read(&self, address: u32, length: u32) -> bool {
+        self.is_register(address) && length <= REG_SPACE
+    }
+
+    /// true if the address is in the flash this controller owns.
+    pub fn is_flash(&self, address: u32) -> bool {
+        self.is_main_array(address) || self.is_user_row(address)
+    }
+
+    fn is_main_array(&self, address: u32) -> bool {
+        address >= self.flash_start && address < self.flash_start + self.flash_size
+    }
+
+    fn is_user_row(&self, address: u32) -> bool {
+        address >= USER_ROW && address < USER_ROW + self.row_size
+    }
+
+    /// Adds the user row to flash with the fuses of a fresh arduino board.
+    pub fn add_user_row(&self, flash: &mut Flash) -> Result<()> {
+        flash.add_block(USER_ROW, self.row_size)?;
+        flash.erase(USER_ROW, self.row_size)?;
+        let words: Vec<u8> = USER_ROW_DEFAULT
+            .iter()
+            .flat_map(|w| w.to_le_bytes())
+            .collect();
+        flash.write(USER_ROW, &words)
+    }
+
+    /// Loads BOOTPROT and the lock bits from the user row,
+    /// the chip does this at reset so changed fuses apply after a restart.
+    pub fn load_fuses(&mut self, flash: &mut Flash) -> Result<()> {
+        let fuses = flash.read(USER_ROW, 8)?;
+        let bootprot = fuses[0] as u32 & BOOTPROT_MASK;
+        self.bootprot = match bootprot {
+            BOOTPROT_NONE => 0,
+            size => 0x8000 >> size,
+        };
+        self.lock = u16::from_le_bytes([fuses[6], fuses[7]]);
+        Ok(())
+    }
+
+    /// The first row in the range that BOOTPROT or a lock region protects.
+    /// only the part of the range inside the main array is looked at.
+    pub fn protected_row(&self, address: u32, length: u32) -> Option<u32> {
+        let first = (address & !(self.row_size - 1)).max(self.flash_start);
+        let end = address
+            .saturating_add(length)
+            .min(self.flash_start + self.flash_size);
+        (first..end)
+            .step_by(self.row_size as usize)
+            .find(|row| self.is_protected(*row))
+    }
+
+    fn is_protected(&self, address: u32) -> bool {
+        self.is_main_array(address)
+            && (address - self.flash_start < self.bootprot || self.is_locked(address))
+    }
+
+    pub fn read(&self, address: u32, length: u32) -> Vec<u8> {
+        let mut regs = [0; REG_SPACE as usize];
+        let ctrla = (CMDEX_KEY << 8).to_le_bytes();
+        regs[CTRLA as usize..CTRLA as usize + 2].copy_from_slice(&ctrla);
+        regs[CTRLB as usize..CTRLB as usize + 4].copy_from_slice(&self.ctrlb.to_le_bytes());
+        regs[PARAM as usize..PARAM as usize + 4].copy_from_slice(&self.param().to_le_bytes());
+        regs[INTFLAG as usize] = self.intflag;
+        regs[STATUS as usize..STATUS as usize + 2].copy_from_slice(&self.status.to_le_bytes());
+        regs[ADDR as usize..ADDR as usize + 4].copy_from_slice(&self.addr.to_le_bytes());
+        regs[LOCK as usize..LOCK as usize + 2].copy_from_slice(&self.lock.to_le_bytes());
+
+        (0..length)
+            .map(|i| {
+                let offset = (address - self.base + i) as usize;
+                regs.get(offset).copied().unwrap_or(0)
+            })
+            .collect()
+    }
+
+    /// Writes a register, returns true if a command it ran changed flash.
+    pub fn write(&mut self, address: u32, data: &[u8], flash: &mut Flash) -> Result<bool> {
+        let mut value = [0; 4];
+        for (v, d) in value.iter_mut().zip(data) {
+            *v = *d;
+        }
+        let value = u32::from_le_bytes(value);
+
+        match address - self.base {
+            CTRLA => return self.command(value as u16, flash),
+            CTRLB => self.ctrlb = value,
+            // flags are cleared by writing a one.
+            INTFLAG => self.intflag &= !(value as u8 & INTFLAG_ERROR),
+            STATUS => self.status &= !(value as u16 & (STATUS_PROGE | STATUS_LOCKE)),
+            ADDR => self.addr = value & 0x3fffff,
+            offset => println!("Nvmctrl ignoring write to {:x}", offset),
+        }
+        Ok(false)
+    }
+
+    /// Loads data written to the flash address space into the page buffer,
+    /// in automatic mode the page is written once its last byte is loaded,
+    /// returns true if that happened.
+    pub fn write_flash(&mut self, address: u32, data: &[u8], flash: &mut Flash) -> Result<bool> {
+        let mut changed = false;
+        for (i, d) in data.iter().enumerate() {
+            let byte_addr = address + i as u32;
+            self.buffer_page = byte_addr & !(self.page_size - 1);
+            let offset = byte_addr - self.buffer_page;
+            self.page_buffer[offset as usize] = *d;
+            self.status |= STATUS_LOAD;
+
+            if self.ctrlb & CTRLB_MANW == 0 && offset == self.page_size - 1 {
+                changed |= self.write_page(self.buffer_page, flash)?;
+            }
+        }
+        Ok(changed)
+    }
+
+    fn command(&mut self, ctrla: u16, flash: &mut Flash) -> Result<bool> {
+        if ctrla >> 8 != CMDEX_KEY {
+            self.set_error(STATUS_PROGE);
+            return Ok(false);
+        }
+        let mut changed = false;
+        // ADDR holds a 16 bit word address.
+        let mut byte_addr = self.flash_start + self.addr * 2;
+        let cmd = ctrla & 0x7f;
+        if cmd == CMD_EAR || cmd == CMD_WAP {
+            // the only auxiliary row mocked is the user row.
+            byte_addr = USER_ROW + (byte_addr & (self.row_size - 1));
+        }
+        match cmd {
+            CMD_ER | CMD_EAR => {
+                let row = byte_addr & !(self.row_size - 1);
+                if self.is_protected(row) {
+                    self.set_error(STATUS_LOCKE);
+                } else {
+                    println!("Nvmctrl erase row {:x}", row);
+                    flash.erase(row, self.row_size)?;
+                    changed = true;
+                }
+            }
+            CMD_WP | CMD_WAP => {
+                let page = byte_addr & !(self.page_size - 1);
+                changed = self.write_page(page, flash)?;
+            }
+            CMD_LR | CMD_UR if !self.is_main_array(byte_addr) => self.set_error(STATUS_PROGE),
+            CMD_LR => self.lock &= !(1 << self.lock_region(byte_addr)),
+            CMD_UR => self.lock |= 1 << self.lock_region(byte_addr),
+            CMD_PBC => self.clear_page_buffer(),
+            cmd => {
+                println!("Nvmctrl unsupported command {:x}", cmd);
+                self.set_error(STATUS_PROGE);
+            }
+        }
+        self.intflag |= INTFLAG_READY;
+        Ok(changed)
+    }
+
+    /// Programs the page buffer into `page`, false if it was protected.
+    fn write_page(&mut self, page: u32, flash: &mut Flash) -> Result<bool> {
+        if self.is_protected(page) {
+            self.set_error(STATUS_LOCKE);
+            return Ok(false);
+        }
+        println!("Nvmctrl write page {:x}", page);
+        // programming can only clear bits, which is why rows need an erase first.
+        let current = flash.read(page, self.page_size)?;
+        let data: Vec<u8> = current
+            .iter()
+            .zip(self.page_buffer.iter())
+            .map(|(c, b)| c & b)
+            .collect();
+        flash.write(page, &data)?;
+        self.clear_page_buffer();
+        Ok(true)
+    }
+
+    fn clear_page_buffer(&mut self) {
+        self.page_buffer.fill(0xff);
+        self.status &= !STATUS_LOAD;
+    }
+
+    /// Flags a rejected write or erase of a locked row, the same way the
+    /// controller does for its own commands.
+    pub fn set_lock_error(&mut self) {
+        self.set_error(STATUS_LOCKE);
+    }
+
+    fn set_error(&mut self, status: u16) {
+        self.status |= status;
+        self.intflag |= INTFLAG_ERROR;
+    }
+
+    fn lock_region(&self, address: u32) -> u32 {
+        (address - self.flash_start) / (self.flash_size / LOCK_REGIONS)
+    }
+
+    fn is_locked(&self, address: u32) -> bool {
+        self.lock & (1 << self.lock_region(address)) == 0
+    }
+
+    // number of pages and the encoded page size.
+    fn param(&self) -> u32 {
+        let pages = self.flash_size / self.page_size;
+        let psz = (self.page_size / 8).trailing_zeros();
+        psz << 16 | pages
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::arduino::Chip;
+
+    const BASE: u32 = 0x41004000;
+
+    fn setup() -> (Nvmctrl, Flash) {
+        let chip = Chip::Samd21g18.profile();
+        let mut flash = Flash::default();
+        flash.add_block(chip.flash.start, chip.flash.size).unwrap();
+        (Nvmctrl::new(BASE, chip), flash)
+    }
+
+    fn command(nvm: &mut Nvmctrl, flash: &mut Flash, addr: u32, cmd: u16) {
+        nvm.write(BASE + ADDR, &(addr / 2).to_le_bytes(), flash)
+            .unwrap();
+        let ctrla = (CMDEX_KEY << 8 | cmd) as u32;
+        nvm.write(BASE + CTRLA, &ctrla.to_le_bytes(), flash)
+            .unwrap();
+    }
+
+    #[test]
+    fn manual_page_write() {
+        let (mut nvm, mut flash) = setup();
+        nvm.write(BASE + CTRLB, &CTRLB_MANW.to_le_bytes(), &mut flash)
+            .unwrap();
+        command(&mut nvm, &mut flash, 0, CMD_PBC);
+        nvm.write_flash(0x2040, &[1, 2, 3, 4], &mut flash).unwrap();
+        // nothing lands until the page is written.
+        assert_eq!(flash.read(0x2040, 4).unwrap(), vec![0xff; 4]);
+        assert_eq!(nvm.read(BASE + STATUS, 2), vec![STATUS_LOAD as u8, 0]);
+
+        command(&mut nvm, &mut flash, 0x2040, CMD_WP);
+        assert_eq!(flash.read(0x2040, 4).unwrap(), vec![1, 2, 3, 4]);
+        assert_eq!(nvm.read(BASE + INTFLAG, 1), vec![INTFLAG_READY]);
+        assert_eq!(nvm.read(BASE + STATUS, 2), vec![0, 0]);
+    }
+
+    #[test]
+    fn automatic_page_write() {
+        let (mut nvm, mut flash) = setup();
+        nvm.write_flash(0x2000, &[0xaa; 60], &mut flash).unwrap();
+        assert_eq!(flash.read(0x2000, 4).unwrap(), vec![0xff; 4]);
+        nvm.write_flash(0x203c, &[0xaa; 4], &mut flash).unwrap();
+        assert_eq!(flash.read(0x2000, 64).unwrap(), vec![0xaa; 64]);
+    }
+
+    #[test]
+    fn erase_row() {
+        let (mut nvm, mut flash) = setup();
+        flash.write(0x2000, &[0; 512]).unwrap();
+        command(&mut nvm, &mut flash, 0x2040, CMD_ER);
+        assert_eq!(flash.read(0x2000, 256).unwrap(), vec![0xff; 256]);
+        assert_eq!(flash.read(0x2100, 256).unwrap(), vec![0; 256]);
+    }
+
+    #[test]
+    fn locked_region() {
+        let (mut nvm, mut flash) = setup();
+        flash.write(0x4000, &[0; 4]).unwrap();
+        command(&mut nvm, &mut flash, 0x4000, CMD_LR);
+        assert_eq!(nvm.read(BASE + LOCK, 2), vec![0xfd, 0xff]);
+
+        command(&mut nvm, &mut flash, 0x4000, CMD_ER);
+        assert_eq!(flash.read(0x4000, 4).unwrap(), vec![0; 4]);
+        assert_eq!(nvm.read(BASE + STATUS, 2), vec![STATUS_LOCKE as u8, 0]);
+        assert_eq!(
+            nvm.read(BASE + INTFLAG, 1),
+            vec![INTFLAG_READY | INTFLAG_ERROR]
+        );
+
+        // clear the error and unlock.
+        nvm.write(BASE + STATUS, &[STATUS_LOCKE as u8, 0], &mut flash)
+            .unwrap();
+        nvm.write(BASE + INTFLAG, &[INTFLAG_ERROR], &mut flash)
+            .unwrap();
+        command(&mut nvm, &mut flash, 0x4000, CMD_UR);
+        command(&mut nvm, &mut flash, 0x4000, CMD_ER);
+        assert_eq!(flash.read(0x4000, 4).unwrap(), vec![0xff; 4]);
+        assert_eq!(nvm.read(BASE + INTFLAG, 1), vec![INTFLAG_READY]);
+    }
+
+    #[test]
+    fn bad_key() {
+        let (mut nvm, mut flash) = setup();
+        nvm.write(BASE + CTRLA, &[CMD_ER as u8, 0x00], &mut flash)
+            .unwrap();
+        assert_eq!(nvm.read(BASE + STATUS, 2), vec![STATUS_PROGE as u8, 0]);
+    }
+
+    fn setup_with_fuses() -> (Nvmctrl, Flash) {
+        let (mut nvm, mut flash) = setup();
+        nvm.add_user_row(&mut flash).unwrap();
+        nvm.load_fuses(&mut flash).unwrap();
+        (nvm, flash)
+    }
+
+    #[test]
+    fn bootprot_protects_bootloader() {
+        let (mut nvm, mut flash) = setup_with_fuses();
+        flash.write(0x1f00, &[0; 0x200]).unwrap();
+        assert_eq!(nvm.protected_row(0x1000, 0x2000), Some(0x1000));
+        assert_eq!(nvm.protected_row(0x2000, 0x100), None);
+
+        command(&mut nvm, &mut flash, 0x1f00, CMD_ER);
+        assert_eq!(flash.read(0x1f00, 4).unwrap(), vec![0; 4]);
+        assert_eq!(nvm.read(BASE + STATUS, 2), vec![STATUS_LOCKE as u8, 0]);
+        nvm.write_flash(0x1fc0, &[0; 64], &mut flash).unwrap();
+        command(&mut nvm, &mut flash, 0x2000, CMD_ER);
+        assert_eq!(flash.read(0x2000, 4).unwrap(), vec![0xff; 4]);
+    }
+
+    #[test]
+    fn fuses_apply_after_reset() {
+        let (mut nvm, mut flash) = setup_with_fuses();
+        // BOOTPROT off and region 2 locked.
+        command(&mut nvm, &mut flash, 0, CMD_EAR);
+        assert_eq!(flash.read(USER_ROW, 4).unwrap(), vec![0xff; 4]);
+        let fuses = [0xd8e0c7ff_u32, 0xfffbfc5d];
+        let fuses: Vec<u8> = fuses.iter().flat_map(|w| w.to_le_bytes()).collect();
+        nvm.write(BASE + CTRLB, &CTRLB_MANW.to_le_bytes(), &mut flash)
+            .unwrap();
+        nvm.write_flash(USER_ROW, &fuses, &mut flash).unwrap();
+        command(&mut nvm, &mut flash, 0, CMD_WAP);
+        assert_eq!(flash.read(USER_ROW, 8).unwrap(), fuses);
+        assert_eq!(nvm.protected_row(0, 4), Some(0));
+
+        nvm.load_fuses(&mut flash).unwrap();
+        assert_eq!(nvm.protected_row(0, 4), None);
+        assert_eq!(nvm.protected_row(0x8000, 4), Some(0x8000));
+        assert_eq!(nvm.read(BASE + LOCK, 2), vec![0xfb, 0xff]);
+    }
+}
@@ -0,0 +1,351 @@
+/// Finds the serial ports arduino style boards show up on.
+/// boards use a different usb pid while they sit in the bootloader,
+/// and come back as a new port after a reset, so lookups are by the
+/// vid/pid table below rather than by port name.
+use std::time::{Duration, Instant};
+
+use serialport::SerialPortType;
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("serial port error: {0}")]
+    SerialPort(#[from] serialport::Error),
+
+    #[error("timed out waiting for a port")]
+    Timeout,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PortKind {
+    Usb {
+        vid: u16,
+        pid: u16,
+        serial_number: Option<String>,
+        manufacturer: Option<String>,
+        product: Option<String>,
+    },
+    Pci,
+    Bluetooth,
+    Unknown,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortInfo {
+    pub name: String,
+    pub kind: PortKind,
+}
+
+impl PortInfo {
+    pub fn usb_ids(&self) -> Option<(u16, u16)> {
+        match self.kind {
+            PortKind::Usb { vid, pid, .. } => Some((vid, pid)),
+            _ => None,
+        }
+    }
+
+    /// The board this port belongs to, if it is in `KNOWN_BOARDS`.
+    pub fn board(&self) -> Option<&'static KnownBoard> {
+        let (vid, pid) = self.usb_ids()?;
+        KNOWN_BOARDS.iter().find(|b| b.vid == vid && b.pid == pid)
+    }
+}
+
+impl From<serialport::SerialPortInfo> for PortInfo {
+    fn from(info: serialport::SerialPortInfo) -> Self {
+        let kind = match info.port_type {
+            SerialPortType::UsbPort(usb) => PortKind::Usb {
+                vid: usb.vid,
+                pid: usb.pid,
+                serial_number: usb.serial_number,
+                manufacturer: usb.manufacturer,
+                product: usb.product,
+            },
+            SerialPortType::PciPort => PortKind::Pci,
+            SerialPortType::BluetoothPort => PortKind::Bluetooth,
+            SerialPortType::Unknown => PortKind::Unknown,
+        };
+        Self {
+            name: info.port_name,
+            kind,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BoardMode {
+    /// running the sam-ba bootloader, ready to be flashed.
+    Bootloader,
+    /// running a sketch.
+    Application,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KnownBoard {
+    pub vid: u16,
+    pub pid: u16,
+    pub name: &'static str,
+    pub mode: BoardMode,
+}
+
+const ARDUINO: u16 = 0x2341;
+const ADAFRUIT: u16 = 0x239a;
+const SPARKFUN: u16 = 0x1b4f;
+const SEEED: u16 = 0x2886;
+
+const fn boot(vid: u16, pid: u16, name: &'static str) -> KnownBoard {
+    KnownBoard {
+        vid,
+        pid,
+        name,
+        mode: BoardMode::Bootloader,
+    }
+}
+
+const fn app(vid: u16, pid: u16, name: &'static str) -> KnownBoard {
+    KnownBoard {
+        vid,
+        pid,
+        name,
+        mode: BoardMode::Application,
+    }
+}
+
+/// samd boards, the application pid is the bootloader pid with bit 15 set.
+pub const KNOWN_BOARDS: &[KnownBoard] = &[
+    boot(ARDUINO, 0x004d, "Arduino Zero"),
+    app(ARDUINO, 0x804d, "Arduino Zero"),
+    boot(ARDUINO, 0x024e, "Arduino MKR1000"),
+    app(ARDUINO, 0x824e, "Arduino MKR1000"),
+    boot(ARDUINO, 0x004f, "Arduino MKRZERO"),
+    app(ARDUINO, 0x804f, "Arduino MKRZERO"),
+    boot(ARDUINO, 0x0054, "Arduino MKR WiFi 1010"),
+    app(ARDUINO, 0x8054, "Arduino MKR WiFi 1010"),
+    boot(ARDUINO, 0x0057, "Arduino Nano 33 IoT"),
+    app(ARDUINO, 0x8057, "Arduino Nano 33 IoT"),
+    boot(ADAFRUIT, 0x000b, "Adafruit Feather M0"),
+    app(ADAFRUIT, 0x800b, "Adafruit Feather M0"),
+    boot(ADAFRUIT, 0x0013, "Adafruit Metro M0 Express"),
+    app(ADAFRUIT, 0x8013, "Adafruit Metro M0 Express"),
+    boot(ADAFRUIT, 0x001e, "Adafruit Trinket M0"),
+    app(ADAFRUIT, 0x801e, "Adafruit Trinket M0"),
+    boot(ADAFRUIT, 0x0022, "Adafruit Feather M4 Express"),
+    app(ADAFRUIT, 0x8022, "Adafruit Feather M4 Express"),
+    boot(SPARKFUN, 0x0d21, "SparkFun SAMD21 Mini"),
+    app(SPARKFUN, 0x8d21, "SparkFun SAMD21 Mini"),
+    boot(SEEED, 0x002d, "Seeed Wio Terminal"),
+    app(SEEED, 0x802d, "Seeed Wio Terminal"),
+    boot(SEEED, 0x002f, "Seeeduino XIAO"),
+    app(SEEED, 0x802f, "Seeeduino XIAO"),
+];
+
+/// Source of the ports currently on the system, tests supply their own.
+pub trait PortLister {
+    fn list(&self) -> Result<Vec<PortInfo>>;
+}
+
+/// Lists the ports the os knows about.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemPorts;
+
+impl PortLister for SystemPorts {
+    fn list(&self) -> Result<Vec<PortInfo>> {
+        Ok(serialport::available_ports()?
+            .into_iter()
+            .map(PortInfo::from)
+            .collect())
+    }
+}
+
+/// A port that belongs to a board in `KNOWN_BOARDS`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FoundBoard {
+    pub port: PortInfo,
+    pub board: &'static KnownBoard,
+}
+
+pub struct PortFinder<L> {
+    lister: L,
+    poll_interval: Duration,
+}
+
+impl PortFinder<SystemPorts> {
+    pub fn new() -> Self {
+        Self::with_lister(SystemPorts)
+    }
+}
+
+impl Default for PortFinder<SystemPorts> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<L: PortLister> PortFinder<L> {
+    pub fn with_lister(lister: L) -> Self {
+        Self {
+            lister,
+            poll_interval: Duration::from_millis(100),
+        }
+    }
+
+    /// How often the port list is checked while waiting.
+    pub fn set_poll_interval(&mut self, interval: Duration) {
+        self.poll_interval = interval;
+    }
+
+    /// Every port, known board or not.
+    pub fn ports(&self) -> Result<Vec<PortInfo>> {
+        self.lister.list()
+    }
+
+    /// Ports belonging to known boards, optionally only those in `mode`.
+    pub fn boards(&self, mode: Option<BoardMode>) -> Result<Vec<FoundBoard>> {
+        Ok(self
+            .ports()?
+            .into_iter()
+            .filter_map(|port| {
+                let board = port.board()?;
+                Some(FoundBoard { port, board })
+            })
+            .filter(|found| mode.is_none_or(|mode| found.board.mode == mode))
+            .collect())
+    }
+
+    /// The first known board, optionally only one in `mode`.
+    pub fn find(&self, mode: Option<BoardMode>) -> Result<Option<FoundBoard>> {
+        Ok(self.boards(mode)?.into_iter().next())
+    }
+
+    /// Polls the port list until a port matches `predicate`.
+    pub fn wait_for<F>(&self, timeout: Duration, mut predicate: F) -> Result<PortInfo>
+    where
+        F: FnMut(&PortInfo) -> bool,
+    {
+        let start = Instant::now();
+        loop {
+            if let Some(port) = self.ports()?.into_iter().find(&mut predicate) {
+                return Ok(port);
+            }
+            if start.elapsed() >= timeout {
+                return Err(Error::Timeout);
+            }
+            std::thread::sleep(self.poll_interval);
+        }
+    }
+
+    /// Waits for a known board in `mode` to show up, for instance
+    /// the bootloader port after a board was reset.
+    pub fn wait_for_board(&self, mode: BoardMode, timeout: Duration) -> Result<FoundBoard> {
+        let port = self.wait_for(timeout, |port| {
+            port.board().is_some_and(|board| board.mode == mode)
+        })?;
+        let board = port.board().expect("matched a known board");
+        Ok(FoundBoard { port, board })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+
+    use super::*;
+
+    /// hands out one port list per call, the last one repeats.
+    struct FakePorts(RefCell<VecDeque<Vec<PortInfo>>>);
+
+    impl FakePorts {
+        fn new(lists: Vec<Vec<PortInfo>>) -> Self {
+            Self(RefCell::new(lists.into()))
+        }
+    }
+
+    impl PortLister for FakePorts {
+        fn list(&self) -> Result<Vec<PortInfo>> {
+            let mut lists = self.0.borrow_mut();
+            if lists.len() > 1 {
+                Ok(lists.pop_front().unwrap())
+            } else {
+                Ok(lists.front().cloned().unwrap_or_default())
+            }
+        }
+    }
+
+    fn usb(name: &str, vid: u16, pid: u16) -> PortInfo {
+        PortInfo {
+            name: name.to_string(),
+            kind: PortKind::Usb {
+                vid,
+                pid,
+                serial_number: None,
+                manufacturer: None,
+                product: None,
+            },
+        }
+    }
+
+    fn other(name: &str, kind: PortKind) -> PortInfo {
+        PortInfo {
+            name: name.to_string(),
+            kind,
+        }
+    }
+
+    #[test]
+    fn known_boards() {
+        let finder = PortFinder::with_lister(FakePorts::new(vec![vec![
+            other("/dev/ttyS0", PortKind::Pci),
+            other("/dev/rfcomm0", PortKind::Bluetooth),
+            other("/dev/pts/3", PortKind::Unknown),
+            usb("/dev/ttyUSB0", 0x0403, 0x6001),
+            usb("/dev/ttyACM0", 0x2341, 0x8057),
+            usb("/dev/ttyACM1", 0x239a, 0x000b),
+        ]]));
+        assert_eq!(finder.ports().unwrap().len(), 6);
+
+        let boards = finder.boards(None).unwrap();
+        assert_eq!(boards.len(), 2);
+        assert_eq!(boards[0].board.name, "Arduino Nano 33 IoT");
+        assert_eq!(boards[0].board.mode, BoardMode::Application);
+
+        let found = finder.find(Some(BoardMode::Bootloader)).unwrap().unwrap();
+        assert_eq!(found.port.name, "/dev/ttyACM1");
+        assert_eq!(found.board.name, "Adafruit Feather M0");
+    }
+
+    #[test]
+    fn table_pairs_modes() {
+        // every bootloader pid has an application pid with bit 15 set.
+        for board in KNOWN_BOARDS {
+            let pair = KNOWN_BOARDS
+                .iter()
+                .find(|b| b.vid == board.vid && b.pid == board.pid ^ 0x8000)
+                .unwrap();
+            assert_eq!(pair.name, board.name);
+            assert_ne!(pair.mode, board.mode);
+        }
+    }
+
+    #[test]
+    fn wait_for_reenumeration() {
+        let mut finder = PortFinder::with_lister(FakePorts::new(vec![
+            vec![usb("/dev/ttyACM0", 0x2886, 0x802f)],
+            vec![],
+            vec![],
+            vec![usb("/dev/ttyACM1", 0x2886, 0x002f)],
+        ]));
+        finder.set_poll_interval(Duration::from_millis(1));
+        let found = finder
+            .wait_for_board(BoardMode::Bootloader, Duration::from_secs(1))
+            .unwrap();
+        assert_eq!(found.port.name, "/dev/ttyACM1");
+        assert_eq!(found.board.name, "Seeeduino XIAO");
+
+        assert!(matches!(
+            finder.wait_for(Duration::from_millis(10), |p| p.name == "/dev/ttyACM9"),
+            Err(Error::Timeout)
+        ));
+    }
+}
@@ -0,0 +1,154 @@
+/// Pseudo terminal the bootloader mock is served on, host tools
+/// such as bossac open the slave side like any other serial port.
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serialport::{SerialPort, TTYPort};
+
+use super::{LineState, Result};
+
+pub struct VirtualPort {
+    master: TTYPort,
+    // kept open so the master does not hang up when a host tool
+    // closes the port between runs.
+    slave: TTYPort,
+    link: Option<PathBuf>,
+}
+
+impl VirtualPort {
+    /// Opens a raw pty pair, if `link` is given a symlink to the slave
+    /// is created there so tools do not need to guess the pts number.
+    pub fn open(link: Option<&Path>) -> Result<Self> {
+        let (mut master, slave) = TTYPort::pair()?;
+        master.set_timeout(Duration::from_millis(100))?;
+
+        let link = match link {
+            Some(link) => {
+                // replace a link left behind by an earlier run.
+                if link.is_symlink() {
+                    std::fs::remove_file(link)?;
+                }
+                let slave_path = slave.name().expect("slave pty has a name");
+                std::os::unix::fs::symlink(slave_path, link)?;
+                Some(link.to_path_buf())
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            master,
+            slave,
+            link,
+        })
+    }
+
+    /// Path of the slave side, this is what host tools should open.
+    pub fn slave_path(&self) -> String {
+        self.slave.name().expect("slave pty has a name")
+    }
+}
+
+impl LineState for VirtualPort {
+    /// the master shares its termios with the slave, so this is the
+    /// rate the host tool opened the slave with.
+    fn baud_rate(&self) -> Option<u32> {
+        SerialPort::baud_rate(&self.master).ok()
+    }
+}
+
+// the bootloader can be served on a plain serial port as well.
+impl LineState for TTYPort {
+    fn baud_rate(&self) -> Option<u32> {
+        SerialPort::baud_rate(self).ok()
+    }
+}
+
+impl LineState for Box<dyn SerialPort> {
+    fn baud_rate(&self) -> Option<u32> {
+        SerialPort::baud_rate(self.as_ref()).ok()
+    }
+}
+
+impl std::io::Read for VirtualPort {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.master.read(buf)
+    }
+}
+
+impl std::io::Write for VirtualPort {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.master.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.master.flush()
+    }
+}
+
+impl Drop for VirtualPort {
+    fn drop(&mut self) {
+        if let Some(link) = &self.link {
+            let _ = std::fs::remove_file(link);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+
+    use super::*;
+    use crate::arduino::Bootloader;
+
+    #[test]
+    fn link_to_slave() {
+        let link = std::env::temp_dir().join(format!("borg-pty-{}", std::process::id()));
+        let mut port = VirtualPort::open(Some(&link)).unwrap();
+        assert_eq!(
+            std::fs::read_link(&link).unwrap(),
+            PathBuf::from(port.slave_path())
+        );
+
+        let mut host = serialport::new(link.to_str().unwrap(), 9600)
+            .timeout(Duration::from_secs(2))
+            .open()
+            .unwrap();
+        host.write_all(b"V#").unwrap();
+        let mut buf = [0; 2];
+        port.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"V#");
+        port.write_all(b"ok").unwrap();
+        host.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ok");
+
+        drop(port);
+        assert!(!link.is_symlink());
+    }
+
+    #[test]
+    fn serves_plain_serial_ports() {
+        let (device, mut host) = TTYPort::pair().unwrap();
+        host.set_timeout(Duration::from_secs(2)).unwrap();
+        let mut bootloader = Bootloader::new(device);
+        host.write_all(b"V#").unwrap();
+        bootloader.update_loop().unwrap();
+        let mut buf = [0; 4];
+        host.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"v2.0");
+
+        let port = VirtualPort::open(None).unwrap();
+        let device = serialport::new(port.slave_path(), 115200).open().unwrap();
+        assert_eq!(LineState::baud_rate(&device), Some(115200));
+        let _bootloader = Bootloader::new(device);
+    }
+
+    #[test]
+    fn sees_host_baud_rate() {
+        let port = VirtualPort::open(None).unwrap();
+        let host = serialport::new(port.slave_path(), 1200).open().unwrap();
+        assert_eq!(port.baud_rate(), Some(1200));
+        drop(host);
+        let _host = serialport::new(port.slave_path(), 115200).open().unwrap();
+        assert_eq!(port.baud_rate(), Some(115200));
+    }
+}
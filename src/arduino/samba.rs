@@ -0,0 +1,293 @@
+//! SAM-BA monitor command framing, without any io.
+//! a command is a letter, an optional hex address followed by ',',
+//! a hex value and a terminating '#', for example `W41004000,A544#`.
+//! the bootloader feeds received bytes through `SambaParser`, the host
+//! side uses `Command::encode` to build what it sends.
+
+/// bossac sends this before its first command to sync the baud rate.
+const AUTOBAUD: u8 = 0x80;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Opcode {
+    /// 'O', write a byte.
+    WriteByte,
+    /// 'H', write a half word.
+    WriteHalfWord,
+    /// 'W', write a word.
+    WriteWord,
+    /// 'o', read a byte.
+    ReadByte,
+    /// 'h', read a half word.
+    ReadHalfWord,
+    /// 'w', read a word.
+    ReadWord,
+    /// 'S', value bytes of data follow the '#'.
+    Send,
+    /// 'R', the device sends value bytes back.
+    Receive,
+    /// 'G', jump to the vector table at value.
+    Go,
+    /// 'V', version string.
+    Version,
+    /// 'T', human readable replies.
+    TerminalMode,
+    /// 'N', binary replies.
+    NormalMode,
+    /// 'X', erase flash from value to the end.
+    Erase,
+    /// 'Y', set the sram buffer (value 0) or copy value bytes of it to flash.
+    CopyBuffer,
+    /// 'Z', crc16 of value bytes at address.
+    Checksum,
+    Unknown(u8),
+}
+
+impl Opcode {
+    pub fn from_byte(byte: u8) -> Self {
+        match byte {
+            b'O' => Self::WriteByte,
+            b'H' => Self::WriteHalfWord,
+            b'W' => Self::WriteWord,
+            b'o' => Self::ReadByte,
+            b'h' => Self::ReadHalfWord,
+            b'w' => Self::ReadWord,
+            b'S' => Self::Send,
+            b'R' => Self::Receive,
+            b'G' => Self::Go,
+            b'V' => Self::Version,
+            b'T' => Self::TerminalMode,
+            b'N' => Self::NormalMode,
+            b'X' => Self::Erase,
+            b'Y' => Self::CopyBuffer,
+            b'Z' => Self::Checksum,
+            other => Self::Unknown(other),
+        }
+    }
+
+    pub fn byte(&self) -> u8 {
+        match self {
+            Self::WriteByte => b'O',
+            Self::WriteHalfWord => b'H',
+            Self::WriteWord => b'W',
+            Self::ReadByte => b'o',
+            Self::ReadHalfWord => b'h',
+            Self::ReadWord => b'w',
+            Self::Send => b'S',
+            Self::Receive => b'R',
+            Self::Go => b'G',
+            Self::Version => b'V',
+            Self::TerminalMode => b'T',
+            Self::NormalMode => b'N',
+            Self::Erase => b'X',
+            Self::CopyBuffer => b'Y',
+            Self::Checksum => b'Z',
+            Self::Unknown(byte) => *byte,
+        }
+    }
+}
+
+/// One parsed command, fields a command does not use are 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Command {
+    pub opcode: Opcode,
+    /// the number before the ',', if there was one.
+    pub address: u32,
+    /// the number before the '#'.
+    pub value: u32,
+}
+
+impl Command {
+    pub fn new(opcode: Opcode, address: u32, value: u32) -> Self {
+        Self {
+            opcode,
+            address,
+            value,
+        }
+    }
+
+    /// A command that only takes a value, like 'X' or 'G'.
+    pub fn with_value(opcode: Opcode, value: u32) -> Self {
+        Self::new(opcode, 0, value)
+    }
+
+    /// A command without arguments, like 'V'.
+    pub fn bare(opcode: Opcode) -> Self {
+        Self::new(opcode, 0, 0)
+    }
+
+    /// The bytes to send for this command, formatted like bossac does.
+    pub fn encode(&self) -> Vec<u8> {
+        let op = self.opcode.byte() as char;
+        let text = match self.opcode {
+            Opcode::Version | Opcode::TerminalMode | Opcode::NormalMode => format!("{op}#"),
+            Opcode::Go | Opcode::Erase => format!("{op}{:08X}#", self.value),
+            _ => format!("{op}{:08X},{:08X}#", self.address, self.value),
+        };
+        text.into_bytes()
+    }
+}
+
+/// Turns received bytes into commands, state carries over between
+/// calls so commands may be split across reads.
+#[derive(Debug, Default, Clone)]
+pub struct SambaParser {
+    opcode: Option<u8>,
+    address: u32,
+    value: u32,
+}
+
+impl SambaParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Forgets any half parsed command.
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Feeds one byte, returns the command once its '#' arrives.
+    pub fn push(&mut self, byte: u8) -> Option<Command> {
+        match byte {
+            b'#' => {
+                let opcode = self.opcode;
+                let command = Command::new(
+                    Opcode::from_byte(opcode.unwrap_or(0)),
+                    self.address,
+                    self.value,
+                );
+                self.reset();
+                match opcode {
+                    None | Some(AUTOBAUD) => None,
+                    Some(_) => Some(command),
+                }
+            }
+            b'0'..=b'9' => self.digit(byte - b'0'),
+            b'A'..=b'F' => self.digit(byte - b'A' + 0xa),
+            b'a'..=b'f' => self.digit(byte - b'a' + 0xa),
+            b',' => {
+                self.address = self.value;
+                self.value = 0;
+                None
+            }
+            // padding, never a command.
+            0xff => None,
+            _ => {
+                self.opcode = Some(byte);
+                self.value = 0;
+                None
+            }
+        }
+    }
+
+    fn digit(&mut self, digit: u8) -> Option<Command> {
+        self.value = self.value << 4 | digit as u32;
+        None
+    }
+
+    /// Feeds bytes until a command completes, returns how many bytes
+    /// were used and the command, bytes after it are left for the
+    /// caller since they may be data belonging to it.
+    pub fn parse(&mut self, data: &[u8]) -> (usize, Option<Command>) {
+        for (i, byte) in data.iter().enumerate() {
+            if let Some(command) = self.push(*byte) {
+                return (i + 1, Some(command));
+            }
+        }
+        (data.len(), None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// every command in `data`, in order.
+    fn parse_all(parser: &mut SambaParser, mut data: &[u8]) -> Vec<Command> {
+        let mut commands = Vec::new();
+        while !data.is_empty() {
+            let (used, command) = parser.parse(data);
+            data = &data[used..];
+            commands.extend(command);
+        }
+        commands
+    }
+
+    #[test]
+    fn parses_bossac_commands() {
+        let mut parser = SambaParser::new();
+        let commands = parse_all(
+            &mut parser,
+            b"\x80\x80#N#V#w41002018,4#W41004000,A544#X2000#G00002000#",
+        );
+        assert_eq!(
+            commands,
+            vec![
+                Command::bare(Opcode::NormalMode),
+                Command::bare(Opcode::Version),
+                Command::new(Opcode::ReadWord, 0x41002018, 4),
+                Command::new(Opcode::WriteWord, 0x41004000, 0xa544),
+                Command::with_value(Opcode::Erase, 0x2000),
+                Command::with_value(Opcode::Go, 0x2000),
+            ]
+        );
+    }
+
+    #[test]
+    fn split_commands_and_trailing_data() {
+        let mut parser = SambaParser::new();
+        assert_eq!(parser.parse(b"S2000"), (5, None));
+        // the data after the '#' is not touched.
+        assert_eq!(
+            parser.parse(b"0000,4#\x01\x02\x03\x04"),
+            (7, Some(Command::new(Opcode::Send, 0x20000000, 4)))
+        );
+        assert_eq!(parser.parse(b"#"), (1, None));
+
+        parser.parse(b"w1234");
+        parser.reset();
+        assert_eq!(parser.parse(b"#"), (1, None));
+        assert_eq!(
+            parser.parse(b"q1#"),
+            (3, Some(Command::with_value(Opcode::Unknown(b'q'), 1)))
+        );
+    }
+
+    #[test]
+    fn encode_round_trips() {
+        let commands = [
+            Command::bare(Opcode::Version),
+            Command::bare(Opcode::TerminalMode),
+            Command::new(Opcode::ReadByte, 0x20000001, 1),
+            Command::new(Opcode::WriteHalfWord, 0x20000004, 0x5566),
+            Command::new(Opcode::Receive, 0x2000, 0x1000),
+            Command::new(Opcode::CopyBuffer, 0x20004000, 0),
+            Command::new(Opcode::Checksum, 0x2000, 0x100),
+            Command::with_value(Opcode::Erase, 0x2000),
+        ];
+        assert_eq!(
+            Command::new(Opcode::WriteWord, 0x10, 0xff).encode(),
+            b"W00000010,000000FF#"
+        );
+        let mut parser = SambaParser::new();
+        for command in commands {
+            assert_eq!(Opcode::from_byte(command.opcode.byte()), command.opcode);
+            assert_eq!(parser.parse(&command.encode()).1, Some(command));
+        }
+    }
+
+    #[test]
+    fn garbage_does_not_panic() {
+        // xorshift, the same garbage every run.
+        let mut state = 0x2545f4914f6cdd1d_u64;
+        let mut parser = SambaParser::new();
+        for _ in 0..10000 {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            parser.push(state as u8);
+        }
+        parser.reset();
+        assert_eq!(parser.parse(b"V#").1, Some(Command::bare(Opcode::Version)));
+    }
+}
@@ -6,6 +6,7 @@ use std::io;
 /// there is some kinda of like sync / ackn setup going on.
 
 const SOH: u8 = 0x01;
+const STX: u8 = 0x02;
 const EOT: u8 = 0x04;
 const ACK: u8 = 0x06;
 const NAK: u8 = 0x15;
@@ -18,15 +19,78 @@ pub enum Error {
     #[error("Invalid packet seq: {0:x?}")]
     InvalidPacketSeq([u8; 2]),
 
+    #[error("Unexpected reply from receiver: {0:x}")]
+    UnexpectedReply(u8),
+
+    #[error("Transfer cancelled by the other side")]
+    Cancelled,
+
+    #[error("Gave up after {0} retries")]
+    TooManyRetries(u32),
+
     #[error("I o error")]
     Io(#[from] std::io::Error),
 }
 
 const PKTLEN_128: u32 = 128;
+const PKTLEN_1K: u32 = 1024;
+
+/// how often a packet, EOT or the handshake is tried before giving up.
+pub const MAX_RETRIES: u32 = 10;
+
+/// How packets are checked, picked by the receiver's first byte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Check {
+    /// 'C', two byte crc16.
+    Crc,
+    /// NAK, the original one byte sum.
+    Checksum,
+}
+
+impl Check {
+    /// what the receiver sends to ask for this kind of packet.
+    fn start_byte(&self) -> u8 {
+        match self {
+            Self::Crc => b'C',
+            Self::Checksum => NAK,
+        }
+    }
+
+    /// bytes after the packet data.
+    fn len(&self) -> usize {
+        match self {
+            Self::Crc => 2,
+            Self::Checksum => 1,
+        }
+    }
+}
+
+/// timeouts spent asking for crc16 packets before a receiver falls
+/// back to checksum packets.
+pub const CRC_TRIES: u32 = 3;
+
+/// Size of the packets the sender uses, receivers take either.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum PacketSize {
+    /// 128 byte SOH packets, all xmodem receivers understand these.
+    #[default]
+    Normal,
+    /// 1024 byte STX packets (xmodem-1k), a short tail still goes
+    /// out as a 128 byte packet to save padding.
+    OneK,
+}
 
 pub struct XmdSerial {
     size_of_data: u32,
     mode_of_transfer: u32,
+    packet_size: PacketSize,
+    crc_tries: u32,
+}
+
+impl Default for XmdSerial {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl XmdSerial {
@@ -34,19 +98,32 @@ impl XmdSerial {
         Self {
             size_of_data: 0,
             mode_of_transfer: 0,
+            packet_size: PacketSize::Normal,
+            crc_tries: CRC_TRIES,
         }
     }
 
+    /// How many times a receiver asks for crc16 packets before falling
+    /// back to checksum packets, 0 only asks for checksum packets.
+    pub fn set_crc_tries(&mut self, crc_tries: u32) {
+        self.crc_tries = crc_tries;
+    }
+
+    /// Packet size used when sending, defaults to 128 byte packets.
+    pub fn set_packet_size(&mut self, packet_size: PacketSize) {
+        self.packet_size = packet_size;
+    }
+
+    /// Receives `length` bytes, 0 takes whatever the sender sends.
+    /// the transfer is started with 'C' for crc16 packets, if the sender
+    /// stays quiet for `crc_tries` timeouts it is asked for checksum
+    /// packets with NAK instead. bad packets are NAKed and resent.
     pub fn serial_getdata_xmd<P: io::Read + io::Write>(
         &mut self,
         comm: &mut P,
         length: u32,
     ) -> Result<Vec<u8>> {
-        // todo: supposed to continously send 'C'
-        // untill remote responds with first byte.
         let mut data = vec![];
-        comm.write_all(&[b'C'])?;
-        let mut tmp_buffer = [0; 1];
 
         if length == 0 {
             self.mode_of_transfer = 1;
@@ -55,92 +132,260 @@ impl XmdSerial {
             self.mode_of_transfer = 0;
         }
 
-        // round up to 128 bytes.
-        // if (length & PKTLEN_128 -1) != 0 {
-        //     length += PKTLEN_128;
-        //     length &= !(PKTLEN_128 - 1);
-        // }
-
-        let mut sno = 1;
-        let mut data_transfered = 0;
-        let mut b_run = true;
-        while b_run {
-            // assumes timeout is set.
-            comm.read_exact(&mut tmp_buffer)?;
-            match tmp_buffer[0] {
-                SOH => {
-                    match self.get_packet(comm, sno) {
-                        Ok(r) => {
-                            data.extend(r);
+        let (check, start) = self.start_transfer(comm)?;
+        let mut header = Some(start);
+        let mut sno: u8 = 1;
+        // no packet taken yet.
+        let mut first = true;
+        let mut retries = 0;
+        loop {
+            match header {
+                // senders may mix both sizes.
+                Some(byte @ (SOH | STX)) => {
+                    let len = if byte == STX { PKTLEN_1K } else { PKTLEN_128 };
+                    match self.get_packet(comm, len, check)? {
+                        Some((seq, packet)) if seq == sno => {
+                            comm.write_all(&[ACK])?;
+                            self.keep(&mut data, packet);
+                            sno = sno.wrapping_add(1);
+                            first = false;
+                            retries = 0;
+                        }
+                        // our ACK got lost and the sender tried again.
+                        Some((seq, _)) if !first && seq == sno.wrapping_sub(1) => {
+                            comm.write_all(&[ACK])?;
                         }
-                        Err(_) => {
-                            b_run = false;
+                        Some((seq, _)) => {
+                            comm.write_all(&[CAN, CAN])?;
+                            return Err(Error::InvalidPacketSeq([seq, !seq]));
+                        }
+                        None => {
+                            retries += 1;
+                            self.reject(comm, check, first)?;
                         }
-                    };
-                    if b_run {
-                        sno += 1;
-                        data_transfered += PKTLEN_128;
                     }
                 }
-                EOT => {
+                Some(EOT) => {
                     comm.write_all(&[ACK])?;
-                    b_run = false;
+                    break;
                 }
+                Some(CAN) => return Err(Error::Cancelled),
+                // noise or nothing at all.
                 _ => {
-                    b_run = false;
+                    retries += 1;
+                    self.reject(comm, check, first)?;
                 }
             }
+            if retries >= MAX_RETRIES {
+                comm.write_all(&[CAN, CAN])?;
+                return Err(Error::TooManyRetries(retries));
+            }
+            header = read_reply(comm)?;
         }
         self.mode_of_transfer = 0;
         Ok(data)
     }
 
-    /// Reads a package with the given sequence number
-    fn get_packet<P: io::Read + io::Write>(&mut self, com: &mut P, sno: u8) -> Result<Vec<u8>> {
-        // sequence buffer, likely a counter of some sort.
-        let (seq, _) = self.get_bytes(com, 2)?;
-        if seq[0] + seq[1] != 0xFF {
-            return Err(Error::InvalidPacketSeq([seq[0], seq[1]]));
-        }
-        // xcrc is the transfered crc
-        // crc is then the calculated crc.
-        let (buffer, xcrc) = self.get_bytes(com, PKTLEN_128)?;
-        let mut tmp_buffer = [0; 2];
-
-        com.read_exact(&mut tmp_buffer)?;
-        let mut crc = (tmp_buffer[0] as u16) << 8;
-        crc += tmp_buffer[1] as u16;
-        if (crc != xcrc) || seq[0] != sno || seq[1] != !sno {
-            // if this happen there is room for a bug to occur.
-            com.write_all(&[CAN])?;
+    /// Asks the sender to start, returns the packet check the sender
+    /// answered to and the first byte it sent.
+    fn start_transfer<P: io::Read + io::Write>(&mut self, comm: &mut P) -> Result<(Check, u8)> {
+        for attempt in 0..MAX_RETRIES {
+            let check = if attempt < self.crc_tries {
+                Check::Crc
+            } else {
+                Check::Checksum
+            };
+            comm.write_all(&[check.start_byte()])?;
+            if let Some(byte) = read_reply(comm)? {
+                return Ok((check, byte));
+            }
+        }
+        Err(Error::TooManyRetries(MAX_RETRIES))
+    }
+
+    /// Throws away the rest of a bad packet and asks for it again.
+    /// before any packet got through the start byte is sent instead of
+    /// NAK, so the sender keeps to our packet check.
+    fn reject<P: io::Read + io::Write>(
+        &mut self,
+        comm: &mut P,
+        check: Check,
+        first: bool,
+    ) -> Result<()> {
+        while read_reply(comm)?.is_some() {}
+        let reply = if first { check.start_byte() } else { NAK };
+        comm.write_all(&[reply])?;
+        Ok(())
+    }
+
+    /// Adds a packet to the received data, padding past the expected
+    /// length is dropped.
+    fn keep(&mut self, data: &mut Vec<u8>, packet: Vec<u8>) {
+        if self.mode_of_transfer != 0 {
+            data.extend(packet);
         } else {
-            com.write_all(&[ACK])?;
+            let wanted = packet.len().min(self.size_of_data as usize);
+            data.extend(&packet[..wanted]);
+            self.size_of_data -= wanted as u32;
         }
-        Ok(buffer)
     }
 
-    // returns the data read and a crc if successfful.
-    fn get_bytes<P: io::Read>(&mut self, com: &mut P, len: u32) -> Result<(Vec<u8>, u16)> {
-        let mut buffer = Vec::with_capacity(len as usize);
-        let mut crc: u16 = 0;
-        let mut cpt = 0;
-        let mut c = [0; 1];
-        while cpt < len {
-            com.read_exact(&mut c)?;
-            crc = serial_add_crc(c[0] as u16, crc);
+    /// Sends data to a receiver, waits for the receiver to start the
+    /// transfer with 'C' (crc16) or NAK (checksum). packets are resent
+    /// when the receiver NAKs them or stays quiet, up to `MAX_RETRIES`.
+    pub fn serial_putdata_xmd<P: io::Read + io::Write>(
+        &mut self,
+        comm: &mut P,
+        data: &[u8],
+    ) -> Result<()> {
+        let mut check = self.wait_for_start(comm)?;
 
-            if self.size_of_data != 0 || self.mode_of_transfer != 0 {
-                buffer.push(c[0]);
-                if len == PKTLEN_128 {
-                    self.size_of_data -= 1;
+        // sequence numbers start at one and wrap.
+        let mut sno: u8 = 1;
+        let mut offset = 0;
+        while offset < data.len() {
+            let remaining = data.len() - offset;
+            let len = match self.packet_size {
+                PacketSize::OneK if remaining > PKTLEN_128 as usize => PKTLEN_1K,
+                _ => PKTLEN_128,
+            };
+            let chunk = &data[offset..offset + remaining.min(len as usize)];
+            let mut retries = 0;
+            loop {
+                self.put_packet(comm, sno, chunk, len, check)?;
+                match read_reply(comm)? {
+                    Some(ACK) => break,
+                    Some(CAN) => return Err(Error::Cancelled),
+                    // NAK, a repeated start byte, noise or nothing at all.
+                    reply => {
+                        // until the first packet is taken the receiver may
+                        // still change its mind about the packet check.
+                        match reply {
+                            Some(b'C') if offset == 0 => check = Check::Crc,
+                            Some(NAK) if offset == 0 => check = Check::Checksum,
+                            _ => {}
+                        }
+                        retries += 1;
+                        if retries >= MAX_RETRIES {
+                            comm.write_all(&[CAN, CAN])?;
+                            return Err(Error::TooManyRetries(retries));
+                        }
+                        println!("xmodem: resending packet {}", sno);
+                    }
                 }
             }
-            cpt += 1;
+            offset += chunk.len();
+            sno = sno.wrapping_add(1);
         }
-        Ok((buffer, crc))
+
+        for _ in 0..MAX_RETRIES {
+            comm.write_all(&[EOT])?;
+            match read_reply(comm)? {
+                Some(ACK) => return Ok(()),
+                Some(CAN) => return Err(Error::Cancelled),
+                _ => {}
+            }
+        }
+        Err(Error::TooManyRetries(MAX_RETRIES))
+    }
+
+    /// Waits for the receiver to ask for the first packet.
+    fn wait_for_start<P: io::Read>(&mut self, comm: &mut P) -> Result<Check> {
+        let mut retries = 0;
+        while retries < MAX_RETRIES {
+            match read_reply(comm)? {
+                Some(b'C') => return Ok(Check::Crc),
+                Some(NAK) => return Ok(Check::Checksum),
+                Some(CAN) => return Err(Error::Cancelled),
+                // left over bytes from before the transfer.
+                Some(_) => {}
+                None => retries += 1,
+            }
+        }
+        Err(Error::TooManyRetries(retries))
+    }
+
+    /// Writes a single packet of `len` bytes, short packets are padded out.
+    fn put_packet<P: io::Write>(
+        &mut self,
+        com: &mut P,
+        sno: u8,
+        data: &[u8],
+        len: u32,
+        check: Check,
+    ) -> Result<()> {
+        let mut buffer = data.to_vec();
+        buffer.resize(len as usize, 0);
+        let header = if len == PKTLEN_1K { STX } else { SOH };
+        com.write_all(&[header, sno, !sno])?;
+        com.write_all(&buffer)?;
+        match check {
+            Check::Crc => com.write_all(&crc16(&buffer).to_be_bytes())?,
+            Check::Checksum => com.write_all(&[checksum(&buffer)])?,
+        }
+        Ok(())
+    }
+
+    /// Reads the rest of a packet of `len` bytes after its header,
+    /// returns its sequence number and data, None if it was cut short
+    /// or does not check out.
+    fn get_packet<P: io::Read>(
+        &mut self,
+        com: &mut P,
+        len: u32,
+        check: Check,
+    ) -> Result<Option<(u8, Vec<u8>)>> {
+        // the sequence number, its complement, the data and then the
+        // crc or checksum.
+        let mut buffer = vec![0; 2 + len as usize + check.len()];
+        if !read_block(com, &mut buffer)? {
+            return Ok(None);
+        }
+        let (seq, rest) = buffer.split_at(2);
+        let (packet, xcheck) = rest.split_at(len as usize);
+        let valid = match check {
+            Check::Crc => crc16(packet).to_be_bytes() == xcheck,
+            Check::Checksum => [checksum(packet)] == xcheck,
+        };
+        if seq[0] != !seq[1] || !valid {
+            return Ok(None);
+        }
+        Ok(Some((seq[0], packet.to_vec())))
     }
 }
 
+/// Reads one byte from the other side, None if nothing came in time.
+fn read_reply<P: io::Read>(comm: &mut P) -> Result<Option<u8>> {
+    let mut byte = [0; 1];
+    Ok(read_block(comm, &mut byte)?.then_some(byte[0]))
+}
+
+/// read_exact, but false if the other side went quiet part way.
+fn read_block<P: io::Read>(comm: &mut P, buffer: &mut [u8]) -> Result<bool> {
+    match comm.read_exact(buffer) {
+        Ok(()) => Ok(true),
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+            ) =>
+        {
+            Ok(false)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// the 8 bit sum used before crc16 came along.
+pub fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, d| sum.wrapping_add(*d))
+}
+
+/// crc16 (ccitt, xmodem flavour) of a block of data.
+pub fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0, |crc, d| serial_add_crc(*d as u16, crc))
+}
+
 fn serial_add_crc(ptr: u16, crc: u16) -> u16 {
     crc << 8 ^ CRC_16_TABLE[((crc >> 8) ^ ptr) as usize & 0xff]
 }
@@ -237,5 +482,322 @@ mod tests {
     }
 
     #[test]
-    fn test_put_xmd() {}
+    fn test_put_xmd() {
+        use crate::arduino::flash_utility::utils::BiChannel;
+        use std::time::Duration;
+
+        let mut sender = BiChannel::new();
+        sender.set_timeout(Duration::from_secs(2));
+        let mut receiver = sender.clone();
+        receiver.set_timeout(Duration::from_secs(2));
+        let data: Vec<u8> = (0..300).map(|i| i as u8).collect();
+
+        let expected = data.clone();
+        let k = std::thread::spawn(move || {
+            let mut s = super::XmdSerial::new();
+            // padding of the last packet is dropped.
+            let r = s.serial_getdata_xmd(&mut receiver, 300).unwrap();
+            assert_eq!(r, expected);
+        });
+        let mut s = super::XmdSerial::new();
+        s.serial_putdata_xmd(&mut sender, &data).unwrap();
+        k.join().unwrap();
+    }
+
+    /// a sender on its own thread and the receiving end of its link.
+    fn spawn_sender(
+        data: Vec<u8>,
+        packet_size: super::PacketSize,
+        timeout: std::time::Duration,
+    ) -> (
+        std::thread::JoinHandle<super::Result<()>>,
+        crate::arduino::flash_utility::utils::BiChannel,
+    ) {
+        use crate::arduino::flash_utility::utils::BiChannel;
+
+        let mut sender = BiChannel::new();
+        sender.set_timeout(timeout);
+        let mut receiver = sender.clone();
+        receiver.set_timeout(std::time::Duration::from_secs(2));
+        let handle = std::thread::spawn(move || {
+            let mut s = super::XmdSerial::new();
+            s.set_packet_size(packet_size);
+            s.serial_putdata_xmd(&mut sender, &data)
+        });
+        (handle, receiver)
+    }
+
+    fn read_bytes<R: Read>(comm: &mut R, length: usize) -> Vec<u8> {
+        let mut buffer = vec![0; length];
+        comm.read_exact(&mut buffer).unwrap();
+        buffer
+    }
+
+    #[test]
+    fn put_retransmits_on_nak() {
+        use super::{ACK, EOT, NAK, SOH};
+        use std::time::Duration;
+
+        let data: Vec<u8> = (0..200).map(|i| i as u8).collect();
+        let (handle, mut receiver) = spawn_sender(
+            data.clone(),
+            super::PacketSize::Normal,
+            Duration::from_secs(2),
+        );
+        receiver.write_all(b"C").unwrap();
+        let first = read_bytes(&mut receiver, 133);
+        assert_eq!(&first[..3], &[SOH, 1, 0xfe]);
+        assert_eq!(&first[3..131], &data[..128]);
+        assert_eq!(&first[131..], &super::crc16(&data[..128]).to_be_bytes());
+        // asking for crc16 again just repeats the packet.
+        receiver.write_all(b"C").unwrap();
+        assert_eq!(read_bytes(&mut receiver, 133), first);
+        receiver.write_all(&[ACK]).unwrap();
+
+        let second = read_bytes(&mut receiver, 133);
+        assert_eq!(&second[..3], &[SOH, 2, 0xfd]);
+        // the short last packet is padded.
+        assert_eq!(&second[75..131], &[0; 56]);
+        receiver.write_all(&[NAK]).unwrap();
+        assert_eq!(read_bytes(&mut receiver, 133), second);
+        receiver.write_all(&[ACK]).unwrap();
+
+        assert_eq!(read_bytes(&mut receiver, 1), [EOT]);
+        receiver.write_all(&[NAK]).unwrap();
+        assert_eq!(read_bytes(&mut receiver, 1), [EOT]);
+        receiver.write_all(&[ACK]).unwrap();
+        handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn put_checksum_mode_and_cancel() {
+        use super::{CAN, NAK};
+        use std::time::Duration;
+
+        let data = vec![0xaa; 128];
+        let (handle, mut receiver) =
+            spawn_sender(data, super::PacketSize::Normal, Duration::from_secs(2));
+        // noise before the handshake is skipped.
+        receiver.write_all(&[0x55, NAK]).unwrap();
+        let packet = read_bytes(&mut receiver, 132);
+        assert_eq!(packet[131], super::checksum(&[0xaa; 128]));
+        receiver.write_all(&[CAN]).unwrap();
+        assert!(matches!(
+            handle.join().unwrap(),
+            Err(super::Error::Cancelled)
+        ));
+    }
+
+    #[test]
+    fn put_gives_up() {
+        use std::time::Duration;
+
+        // nobody starts the transfer.
+        let (handle, _receiver) = spawn_sender(
+            vec![1; 10],
+            super::PacketSize::Normal,
+            Duration::from_millis(5),
+        );
+        assert!(matches!(
+            handle.join().unwrap(),
+            Err(super::Error::TooManyRetries(10))
+        ));
+
+        // the receiver starts it, then goes quiet.
+        let (handle, mut receiver) = spawn_sender(
+            vec![1; 10],
+            super::PacketSize::Normal,
+            Duration::from_millis(5),
+        );
+        receiver.write_all(b"C").unwrap();
+        assert!(matches!(
+            handle.join().unwrap(),
+            Err(super::Error::TooManyRetries(10))
+        ));
+        // every try went out, then the transfer was cancelled.
+        let sent = read_bytes(&mut receiver, 133 * 10 + 2);
+        assert_eq!(&sent[133 * 10..], &[super::CAN, super::CAN]);
+    }
+
+    #[test]
+    fn put_1k_packets() {
+        use super::{ACK, EOT, SOH, STX};
+        use std::time::Duration;
+
+        // two full 1k packets, the 52 left over fit a 128 byte packet.
+        let data: Vec<u8> = (0..2100u32).map(|i| (i * 7) as u8).collect();
+        let (handle, mut receiver) = spawn_sender(
+            data.clone(),
+            super::PacketSize::OneK,
+            Duration::from_secs(2),
+        );
+        receiver.write_all(b"C").unwrap();
+        for (sno, range) in [(1u8, 0..1024), (2, 1024..2048)] {
+            let packet = read_bytes(&mut receiver, 1029);
+            assert_eq!(&packet[..3], &[STX, sno, !sno]);
+            assert_eq!(&packet[3..1027], &data[range.clone()]);
+            assert_eq!(&packet[1027..], &super::crc16(&data[range]).to_be_bytes());
+            receiver.write_all(&[ACK]).unwrap();
+        }
+        let tail = read_bytes(&mut receiver, 133);
+        assert_eq!(&tail[..3], &[SOH, 3, 0xfc]);
+        assert_eq!(&tail[3..55], &data[2048..]);
+        receiver.write_all(&[ACK]).unwrap();
+        assert_eq!(read_bytes(&mut receiver, 1), [EOT]);
+        receiver.write_all(&[ACK]).unwrap();
+        handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn get_mixed_packet_sizes() {
+        use crate::arduino::flash_utility::utils::BiChannel;
+        use std::time::Duration;
+
+        // 1k packets with a 128 byte tail, 1k packets with a padded
+        // tail, and a transfer too short for a 1k packet.
+        for length in [2100, 3000, 100] {
+            let mut sender = BiChannel::new();
+            sender.set_timeout(Duration::from_secs(2));
+            let mut receiver = sender.clone();
+            receiver.set_timeout(Duration::from_secs(2));
+            let data: Vec<u8> = (0..length).map(|i| (i * 3) as u8).collect();
+
+            let expected = data.clone();
+            let k = std::thread::spawn(move || {
+                let mut s = super::XmdSerial::new();
+                let r = s.serial_getdata_xmd(&mut receiver, length).unwrap();
+                assert_eq!(r, expected);
+            });
+            let mut s = super::XmdSerial::new();
+            s.set_packet_size(super::PacketSize::OneK);
+            s.serial_putdata_xmd(&mut sender, &data).unwrap();
+            k.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn put_follows_checksum_fallback() {
+        use super::{ACK, EOT, NAK};
+        use std::time::Duration;
+
+        let data = vec![0x42; 100];
+        let (handle, mut receiver) = spawn_sender(
+            data.clone(),
+            super::PacketSize::Normal,
+            Duration::from_secs(2),
+        );
+        receiver.write_all(b"C").unwrap();
+        read_bytes(&mut receiver, 133);
+        // the receiver gave up on crc16 before the packet came in.
+        receiver.write_all(&[NAK]).unwrap();
+        let packet = read_bytes(&mut receiver, 132);
+        assert_eq!(&packet[3..103], &data[..]);
+        assert_eq!(packet[131], super::checksum(&packet[3..131]));
+        receiver.write_all(&[ACK]).unwrap();
+        assert_eq!(read_bytes(&mut receiver, 1), [EOT]);
+        receiver.write_all(&[ACK]).unwrap();
+        handle.join().unwrap().unwrap();
+    }
+
+    /// the sending end of a link with a receiver on its own thread.
+    fn spawn_receiver(
+        length: u32,
+        crc_tries: u32,
+        timeout: std::time::Duration,
+    ) -> (
+        std::thread::JoinHandle<super::Result<Vec<u8>>>,
+        crate::arduino::flash_utility::utils::BiChannel,
+    ) {
+        use crate::arduino::flash_utility::utils::BiChannel;
+
+        let mut receiver = BiChannel::new();
+        receiver.set_timeout(timeout);
+        let mut sender = receiver.clone();
+        sender.set_timeout(std::time::Duration::from_secs(2));
+        let handle = std::thread::spawn(move || {
+            let mut s = super::XmdSerial::new();
+            s.set_crc_tries(crc_tries);
+            s.serial_getdata_xmd(&mut receiver, length)
+        });
+        (handle, sender)
+    }
+
+    #[test]
+    fn get_falls_back_to_checksum() {
+        use super::{ACK, EOT, NAK, SOH};
+        use std::time::Duration;
+
+        let (handle, mut sender) = spawn_receiver(5, 3, Duration::from_millis(20));
+        // a sender that only knows checksums ignores the 'C's.
+        assert_eq!(read_bytes(&mut sender, 4), [b'C', b'C', b'C', NAK]);
+        let mut packet = [0; 128];
+        packet[..5].copy_from_slice(b"hello");
+        let mut bad = vec![SOH, 1, 0xfe];
+        bad.extend(packet);
+        bad.push(super::checksum(&packet) ^ 1);
+        sender.write_all(&bad).unwrap();
+        // a bad first packet gets the start byte again.
+        assert_eq!(read_bytes(&mut sender, 1), [NAK]);
+        *bad.last_mut().unwrap() ^= 1;
+        sender.write_all(&bad).unwrap();
+        assert_eq!(read_bytes(&mut sender, 1), [ACK]);
+        sender.write_all(&[EOT]).unwrap();
+        assert_eq!(read_bytes(&mut sender, 1), [ACK]);
+        assert_eq!(handle.join().unwrap().unwrap(), b"hello");
+
+        // nobody ever answers.
+        let (handle, _sender) = spawn_receiver(5, 3, Duration::from_millis(5));
+        assert!(matches!(
+            handle.join().unwrap(),
+            Err(super::Error::TooManyRetries(10))
+        ));
+    }
+
+    #[test]
+    fn crc_receiver_and_checksum_only_sender() {
+        use std::time::Duration;
+
+        let (handle, mut sender) = spawn_receiver(300, 2, Duration::from_millis(20));
+        let data: Vec<u8> = (0..300).map(|i| (i * 5) as u8).collect();
+        // skip the 'C's like an old sender would, then let the
+        // sender pick up the NAK.
+        read_bytes(&mut sender, 2);
+        super::XmdSerial::new()
+            .serial_putdata_xmd(&mut sender, &data)
+            .unwrap();
+        assert_eq!(handle.join().unwrap().unwrap(), data);
+    }
+
+    #[test]
+    fn round_trip_over_noisy_link() {
+        use crate::arduino::flash_utility::utils::LinkModel;
+        use std::time::Duration;
+
+        let data: Vec<u8> = (0..3000u32).map(|i| (i * 11) as u8).collect();
+        // a few of these seeds corrupt packets, some more than once.
+        for seed in 1..=8 {
+            // the receiver clears the line before asking again, the
+            // sender has to wait longer than that for the reply.
+            let (handle, mut sender) = spawn_receiver(3000, 3, Duration::from_millis(50));
+            sender.set_timeout(Duration::from_millis(200));
+            sender.set_link(LinkModel {
+                bit_flip_rate: 0.0002,
+                drop_rate: 0.0001,
+                duplicate_rate: 0.0001,
+                seed,
+                ..Default::default()
+            });
+            let mut s = super::XmdSerial::new();
+            s.set_packet_size(super::PacketSize::OneK);
+            s.serial_putdata_xmd(&mut sender, &data).unwrap();
+            assert_eq!(handle.join().unwrap().unwrap(), data, "seed {seed}");
+        }
+    }
+
+    #[test]
+    fn test_crc16() {
+        assert_eq!(super::crc16(b""), 0);
+        assert_eq!(super::crc16(b"123456789"), 0x31c3);
+        assert_eq!(super::checksum(&[0xff, 0x02, 0x10]), 0x11);
+    }
 }
@@ -0,0 +1,332 @@
+/// Host side flasher for arduino sam-ba bootloaders, takes the same
+/// core flags as bossac so it can stand in for it.
+/// `flasher -p /tmp/borg-samd21g18 -e -w -v -R firmware.bin`
+use std::time::Duration;
+
+use factorio_calculator::arduino::flash_utility::{self, ArduinoBootComm, CancelToken, Flasher};
+use factorio_calculator::arduino::port_finder::{BoardMode, PortFinder};
+use factorio_calculator::arduino::TransportMode;
+
+const USAGE: &str = "usage: flasher [-p PORT] [-e] [-w] [-v] [-r] [-o OFFSET] [-b] [-R] [-i] [-U BOOL] [-a] [--debug] [--incremental] [--force] [FILE]";
+
+#[derive(Debug, Default, PartialEq)]
+struct Options {
+    port: Option<String>,
+    erase: bool,
+    write: bool,
+    verify: bool,
+    read: bool,
+    /// defaults to the end of the bootloader.
+    offset: Option<u32>,
+    boot: bool,
+    reset: bool,
+    info: bool,
+    debug: bool,
+    /// allow writing over the bootloader.
+    force: bool,
+    /// only write rows that differ from what is in flash.
+    incremental: bool,
+    /// force the port to be treated as usb or not.
+    usb: Option<bool>,
+    /// reset the board into its bootloader with a 1200 baud touch first.
+    touch: bool,
+    file: Option<String>,
+}
+
+fn parse_number(value: &str) -> Result<u32, String> {
+    let parsed = match value.strip_prefix("0x").or(value.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+    parsed.map_err(|_| format!("invalid number: {value}"))
+}
+
+fn parse_bool(value: &str) -> Result<bool, String> {
+    match value {
+        "1" | "true" => Ok(true),
+        "0" | "false" => Ok(false),
+        _ => Err(format!("invalid bool: {value}")),
+    }
+}
+
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Options, String> {
+    let mut options = Options::default();
+    while let Some(arg) = args.next() {
+        // long options may carry their value after an '='.
+        let (name, inline) = match arg.split_once('=') {
+            Some((name, value)) if arg.starts_with("--") => {
+                (name.to_string(), Some(value.to_string()))
+            }
+            _ => (arg.clone(), None),
+        };
+        let mut value = |name: &str| {
+            inline
+                .clone()
+                .or_else(|| args.next())
+                .ok_or_else(|| format!("{name} needs a value"))
+        };
+
+        match name.as_str() {
+            "-p" | "--port" => options.port = Some(value(&name)?),
+            "-o" | "--offset" => options.offset = Some(parse_number(&value(&name)?)?),
+            "-U" | "--usb-port" => options.usb = Some(parse_bool(&value(&name)?)?),
+            "-e" | "--erase" => options.erase = true,
+            "-w" | "--write" => options.write = true,
+            "-v" | "--verify" => options.verify = true,
+            "-r" | "--read" => options.read = true,
+            "-b" | "--boot" => options.boot = true,
+            "-R" | "--reset" => options.reset = true,
+            "-i" | "--info" => options.info = true,
+            "-d" | "--debug" => options.debug = true,
+            "-a" | "--touch" => options.touch = true,
+            "--incremental" => options.incremental = true,
+            "--force" => options.force = true,
+            flags if flags.starts_with('-') && !flags.starts_with("--") && flags.len() > 2 => {
+                // bundled short flags such as -ewv.
+                for flag in flags[1..].chars() {
+                    match flag {
+                        'e' => options.erase = true,
+                        'w' => options.write = true,
+                        'v' => options.verify = true,
+                        'r' => options.read = true,
+                        'b' => options.boot = true,
+                        'R' => options.reset = true,
+                        'i' => options.info = true,
+                        'd' => options.debug = true,
+                        'a' => options.touch = true,
+                        _ => return Err(format!("unknown flag: -{flag}")),
+                    }
+                }
+            }
+            other if other.starts_with('-') => return Err(format!("unknown option: {other}")),
+            _ => options.file = Some(arg),
+        }
+    }
+
+    if (options.write || options.verify || options.read) && options.file.is_none() {
+        return Err("a file is needed to write, verify or read".to_string());
+    }
+    Ok(options)
+}
+
+fn run(options: Options) -> Result<(), Box<dyn std::error::Error>> {
+    let port_name = match options.port.clone() {
+        Some(port) => port,
+        // without -p pick the first board sitting in its bootloader,
+        // or one running a sketch when it is going to be touched anyway.
+        None => {
+            let finder = PortFinder::new();
+            let found = match finder.find(Some(BoardMode::Bootloader))? {
+                Some(found) => Some(found),
+                None if options.touch => finder.find(Some(BoardMode::Application))?,
+                None => None,
+            }
+            .ok_or("no port given and no board in bootloader mode found")?;
+            println!("Using {} on {}", found.board.name, found.port.name);
+            found.port.name
+        }
+    };
+    // bossac accepts bare names like ttyACM0.
+    let port_name = if port_name.starts_with('/') {
+        port_name
+    } else {
+        format!("/dev/{port_name}")
+    };
+    let port_name = if options.touch {
+        let port = flash_utility::enter_bootloader(
+            &port_name,
+            &PortFinder::new(),
+            Duration::from_secs(10),
+        )?;
+        println!("Bootloader on {port}");
+        port
+    } else {
+        port_name
+    };
+    let usb = options
+        .usb
+        .unwrap_or(port_name.contains("ACM") || port_name.contains("usbmodem"));
+    let port = serialport::new(&port_name, 921600)
+        .timeout(Duration::from_secs(2))
+        .open()?;
+
+    let mut comm = ArduinoBootComm::new(port);
+    comm.set_debug(options.debug);
+    if usb {
+        comm.set_transport_mode(TransportMode::UsbRaw);
+    }
+    // binary replies, a previous session may have left terminal mode on.
+    comm.set_normal_mode()?;
+    let mut flasher = Flasher::identify(comm)?;
+    flasher.set_force(options.force);
+    // ctrl-c stops at the next page instead of killing a write halfway.
+    let cancel = CancelToken::new();
+    flasher.set_cancel_token(cancel.clone());
+    ctrlc::set_handler(move || cancel.cancel())?;
+    flasher.set_progress(Box::new(|phase, done, total| {
+        let percent = (done as u64 * 100).checked_div(total as u64).unwrap_or(100);
+        print!("\r{:?}: {}% ({}/{})", phase, percent, done, total);
+        std::io::Write::flush(&mut std::io::stdout()).ok();
+        if done == total {
+            println!();
+        }
+    }));
+    let device = flasher.device();
+    let offset = options.offset.unwrap_or(device.bootloader_size());
+
+    if options.info {
+        let version = flasher.comm().version()?;
+        println!("Version       : {}", version);
+        println!("Device        : {}", device.name());
+        println!(
+            "Pages         : {}",
+            device.flash_size() / device.page_size()
+        );
+        println!("Page Size     : {} bytes", device.page_size());
+    }
+    // an incremental write erases the rows it needs by itself.
+    if options.erase && !options.incremental {
+        println!("Erase flash");
+        flasher.erase(offset)?;
+        println!("Done");
+    }
+    let file = options.file.as_deref().unwrap_or_default();
+    if options.write {
+        let data = std::fs::read(file)?;
+        println!("Write {} bytes to flash at {:#x}", data.len(), offset);
+        if options.incremental {
+            let report = flasher.write_changed(offset, &data)?;
+            println!(
+                "Wrote {} of {} rows, {} bytes unchanged",
+                report.rows_written, report.rows, report.bytes_saved
+            );
+            if report.bytes_erased_past > 0 {
+                println!(
+                    "Erased {} bytes of flash after the image, this device can only erase to the end",
+                    report.bytes_erased_past
+                );
+            }
+        } else {
+            flasher.write(offset, &data)?;
+        }
+    }
+    if options.verify {
+        let data = std::fs::read(file)?;
+        println!("Verify {} bytes of flash", data.len());
+        flasher.verify(offset, &data)?;
+        println!("Verify successful");
+    }
+    if options.read {
+        let size = device
+            .flash_size()
+            .checked_sub(offset)
+            .ok_or("offset is past the end of flash")?;
+        println!("Read {} bytes from flash", size);
+        let data = flasher.read(offset, size)?;
+        std::fs::write(file, data)?;
+    }
+    if options.boot {
+        // only the sam3 has a boot select bit.
+        flasher.set_boot_flash()?;
+        println!("Set boot flash true");
+    }
+    if options.reset {
+        flasher.reset()?;
+    }
+    Ok(())
+}
+
+fn main() {
+    let options = match parse_args(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{e}");
+            eprintln!("{USAGE}");
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = run(options) {
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Options, String> {
+        parse_args(args.iter().map(|a| a.to_string()))
+    }
+
+    #[test]
+    fn arduino_ide_invocation() {
+        let options = parse(&[
+            "-i",
+            "-d",
+            "--port=ttyACM0",
+            "-U",
+            "true",
+            "-i",
+            "-e",
+            "-w",
+            "-v",
+            "fw.bin",
+            "-R",
+        ])
+        .unwrap();
+        assert_eq!(
+            options,
+            Options {
+                port: Some("ttyACM0".to_string()),
+                erase: true,
+                write: true,
+                verify: true,
+                reset: true,
+                info: true,
+                debug: true,
+                usb: Some(true),
+                file: Some("fw.bin".to_string()),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn bundled_flags_and_offset() {
+        let options = parse(&[
+            "-p",
+            "/tmp/borg",
+            "-ewv",
+            "-o",
+            "0x2000",
+            "--incremental",
+            "fw.bin",
+        ])
+        .unwrap();
+        assert!(options.incremental);
+        assert!(!options.touch);
+        assert!(options.erase && options.write && options.verify);
+        assert_eq!(options.offset, Some(0x2000));
+        assert_eq!(options.port.as_deref(), Some("/tmp/borg"));
+        assert_eq!(
+            parse(&["--offset=8192", "-r", "out.bin"]).unwrap().offset,
+            Some(8192)
+        );
+    }
+
+    #[test]
+    fn touch_flag() {
+        assert!(parse(&["-a", "-p", "ttyACM0"]).unwrap().touch);
+        assert!(parse(&["-aewv", "fw.bin"]).unwrap().touch);
+        assert!(parse(&["--touch"]).unwrap().touch);
+    }
+
+    #[test]
+    fn bad_args() {
+        assert!(parse(&["-w"]).is_err());
+        assert!(parse(&["-p"]).is_err());
+        assert!(parse(&["-x"]).is_err());
+        assert!(parse(&["-o", "zz", "fw.bin"]).is_err());
+    }
+}
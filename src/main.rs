@@ -1,18 +1,17 @@
 use factorio::{FactorioState, Input};
+use factorio_calculator::arduino;
+use factorio_calculator::arduino::flash_utility::Flasher;
+use factorio_calculator::arduino::port_finder::PortFinder;
 use ggez::event;
 use ggez::glam::*;
 use ggez::graphics::{self, Color};
 use ggez::{Context, GameResult};
 use i2c::ProtocolState;
-use serialport::available_ports;
 use serialport::SerialPort;
-use serialport::SerialPortType;
-use std::io;
 
 use std::time::Duration;
 use std::time::Instant;
 
-mod arduino;
 mod factorio;
 mod i2c;
 
@@ -128,126 +127,102 @@ impl event::EventHandler<ggez::GameError> for MainState {
     }
 }
 
+/// Finds the port of the first known arduino style board.
 pub fn get_port() -> Option<String> {
-    match available_ports() {
-        Ok(ports) => {
-            match ports.len() {
-                0 => println!("No ports found."),
-                1 => println!("Found 1 port:"),
-                n => println!("Found {} ports:", n),
-            }
-            for p in ports {
-                println!("   {}", p.port_name);
-                match p.port_type {
-                    SerialPortType::UsbPort(info) => {
-                        println!(" Type::USB");
-                        println!(" VID: 0x{:04x} PID: 0x{:04x}", info.vid, info.pid);
-                        if info.vid == 0x2341 {
-                            println!("Found arduino port thingy");
-                            return Some(p.port_name);
-                        }
-                        println!(
-                            "     Serial Number: {}",
-                            info.serial_number.as_ref().map_or("", String::as_str)
-                        );
-                        println!(
-                            "      Manufacturer: {}",
-                            info.manufacturer.as_ref().map_or("", String::as_str)
-                        );
-                        println!(
-                            "           Product: {}",
-                            info.product.as_ref().map_or("", String::as_str)
-                        );
-                        #[cfg(feature = "usbportinfo-interface")]
-                        println!(
-                            "         Interface: {}",
-                            info.interface
-                                .as_ref()
-                                .map_or("".to_string(), |x| format!("{:02x}", *x))
-                        );
-                    }
-                    SerialPortType::PciPort => todo!(),
-                    SerialPortType::BluetoothPort => todo!(),
-                    SerialPortType::Unknown => todo!(),
-                }
-            }
+    match PortFinder::new().find(None) {
+        Ok(Some(found)) => {
+            println!(
+                "Found {} ({:?}) on {}",
+                found.board.name, found.board.mode, found.port.name
+            );
+            Some(found.port.name)
+        }
+        Ok(None) => None,
+        Err(e) => {
+            eprintln!("Failed to list ports: {e}");
+            None
         }
-        Err(_) => todo!(),
     }
-    None
 }
 
 /// expects port to have a timeout.
-fn dump_memory(mut port: Box<dyn SerialPort>, mut start_addr: u32, length: usize) {
-    let mut buffer = [0; 1];
-    let mut byte_read_count = 0;
-
-    while byte_read_count < length {
-        let addr = start_addr.to_be_bytes();
-        println!("Reading data from: {:x?}", addr);
-        port.write_all(&addr).expect("Failed to write address");
-        port.write_all(b",").expect("Failed to write numeric eol");
-        port.write_all(b"o#")
-            .expect("Failed to send command to arduino");
-        match port.read(&mut buffer) {
-            Ok(bytes) => {
-                if bytes == 1 {
-                    println!("{:#02x}: {:#02x}", start_addr, buffer[0]);
-                }
+fn dump_memory(port: Box<dyn SerialPort>, start_addr: u32, length: usize) {
+    let mut flasher = Flasher::new(port);
+    flasher.set_progress(Box::new(|_, done, total| {
+        print!("\rReading {}/{}", done, total);
+        std::io::Write::flush(&mut std::io::stdout()).ok();
+        if done == total {
+            println!();
+        }
+    }));
+    match flasher.read(start_addr, length as u32) {
+        Ok(data) => {
+            for (i, line) in data.chunks(16).enumerate() {
+                println!("{:#010x}: {:02x?}", start_addr as usize + i * 16, line);
             }
-            Err(ref e) if e.kind() == io::ErrorKind::TimedOut => break,
-            Err(e) => eprintln!("{:?}", e),
         }
-        byte_read_count += 1;
-
-        start_addr += 1;
+        Err(e) => eprintln!("{:?}", e),
     }
 }
 
 pub fn main() -> GameResult {
     env_logger::init();
+    // `dump OFFSET LENGTH [PORT]` prints the flash of a board instead of
+    // serving one, without a port the first known board is used.
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("dump") {
+        let number = |arg: Option<&String>| {
+            arg.and_then(|arg| match arg.strip_prefix("0x") {
+                Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                None => arg.parse().ok(),
+            })
+        };
+        let (Some(start), Some(length)) = (number(args.get(2)), number(args.get(3))) else {
+            eprintln!("usage: dump OFFSET LENGTH [PORT]");
+            return Ok(());
+        };
+        let Some(port_name) = args.get(4).cloned().or_else(get_port) else {
+            eprintln!("No board found");
+            return Ok(());
+        };
+        match serialport::new(&port_name, 921600)
+            .timeout(Duration::from_secs(2))
+            .open()
+        {
+            Ok(port) => dump_memory(port, start, length as usize),
+            Err(e) => eprintln!("Failed to open {port_name}: {e}"),
+        }
+        return Ok(());
+    }
     println!("Ready");
-    //let port_name = get_port().expect("Failed to find port");
-    let port_name = "/dev/pts/5";
-    let mut port = serialport::new(port_name, 9600)
-        .open()
-        .expect("Failed to open serial port");
-    port.set_timeout(Duration::from_millis(40000))
-        .expect("Failed to set timeout");
-    port.clear(serialport::ClearBuffer::Input)
-        .expect("failed to clear buffer");
-    // not sure if needed.
-    //port.clear(ClearBuffer::Output).expect("Failed to clear output buffer");
-
-    let mut bootloader = arduino::Bootloader::new(port);
-    loop {
-        bootloader.update_loop().expect("failed bootloader loop");
+    // optionally pick which chip to pretend to be, defaults to a samd21g18.
+    let chip = std::env::args()
+        .nth(1)
+        .map(|name| name.parse().expect("Unknown chip"))
+        .unwrap_or(arduino::Chip::Samd21g18);
+    let link = std::path::PathBuf::from(format!("/tmp/borg-{}", chip.profile().name));
+    let port = arduino::VirtualPort::open(Some(&link)).expect("Failed to open pty");
+    println!("Serving {} on {} ({})", chip.profile().name, link.display(), port.slave_path());
+    let mut bootloader = arduino::Bootloader::with_chip(port, chip);
+    // optionally keep the flash in a file so it survives restarts.
+    if let Some(path) = std::env::args().nth(2) {
+        bootloader
+            .set_image(arduino::ImageOptions {
+                path: path.into(),
+                load_on_start: true,
+                save_on_exit: true,
+                // ctrl-c skips destructors, so save as flash changes too.
+                save_on_change: true,
+            })
+            .expect("Failed to load flash image");
     }
-
-    return Ok(());
-    let mut buffer: [u8; 1] = [0; 1];
-    println!("Attempting to read out version information");
-    port.write_all(&[b'V', b'#'])
-        .expect("failed to write some bytes");
-    let mut reading_version = false;
     loop {
-        match port.read(&mut buffer) {
-            Ok(bytes) => {
-                if bytes == 1 {
-                    println!("Received: {:?}", buffer[0] as char);
-                    reading_version = true;
-                }
-            }
-            Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {
-                if reading_version {
-                    break;
-                }
-            }
-            Err(e) => eprintln!("{:?}", e),
+        // a rejected command should not take the whole mock down.
+        if let Err(e) = bootloader.update_loop() {
+            println!("Bootloader error: {e}");
         }
     }
-    println!("Attempting to read some bytes");
-    dump_memory(port, 0x2000, 0x100);
+
     return Ok(());
 
     let cb = ggez::ContextBuilder::new("super_simple", "ggez")